@@ -10,8 +10,10 @@ extern crate env_logger;
 extern crate libremarkable;
 use libremarkable::framebuffer::cgmath;
 use libremarkable::framebuffer::common::*;
-use libremarkable::framebuffer::refresh::PartialRefreshMode;
+use libremarkable::framebuffer::refresh::{PartialRefreshMode, RefreshProfile};
+use libremarkable::framebuffer::scheduler::SchedulerConfig;
 use libremarkable::framebuffer::storage;
+use libremarkable::framebuffer::temperature::TemperatureMode;
 use libremarkable::framebuffer::{FramebufferDraw, FramebufferIO, FramebufferRefresh};
 use libremarkable::image::GenericImage;
 use libremarkable::input::{gpio, multitouch, wacom, InputDevice};
@@ -29,6 +31,7 @@ use chrono::{DateTime, Local};
 extern crate atomic;
 use atomic::Atomic;
 
+use std::fs::File;
 use std::process::Command;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
@@ -94,6 +97,9 @@ const CANVAS_REGION: mxcfb_rect = mxcfb_rect {
     width: 1404,
 };
 
+// Where the saved canvas is persisted so that it survives a restart of the demo
+const SAVED_CANVAS_PATH: &str = "demo-canvas.lrcs";
+
 lazy_static! {
     static ref G_TOUCH_MODE: Atomic<TouchMode> = Atomic::new(TouchMode::OnlyUI);
     static ref G_DRAW_MODE: Atomic<DrawMode> = Atomic::new(DrawMode::Draw(2));
@@ -101,7 +107,6 @@ lazy_static! {
     static ref WACOM_IN_RANGE: AtomicBool = AtomicBool::new(false);
     static ref WACOM_HISTORY: Mutex<Vec<cgmath::Point2<i32>>> = Mutex::new(Vec::new());
     static ref G_COUNTER: Mutex<u32> = Mutex::new(0);
-    static ref LAST_REFRESHED_CANVAS_RECT: Atomic<mxcfb_rect> = Atomic::new(mxcfb_rect::invalid());
     static ref SAVED_CANVAS: Mutex<Option<storage::CompressedCanvasState>> = Mutex::new(None);
 }
 
@@ -115,12 +120,21 @@ fn on_save_canvas(app: &mut appctx::ApplicationContext, _element: UIElementHandl
     match framebuffer.dump_region(CANVAS_REGION) {
         Err(err) => println!("Failed to dump buffer: {0}", err),
         Ok(buff) => {
-            let mut hist = SAVED_CANVAS.lock().unwrap();
-            *hist = Some(storage::CompressedCanvasState::new(
+            let state = storage::CompressedCanvasState::new(
                 buff.as_slice(),
                 CANVAS_REGION.height,
                 CANVAS_REGION.width,
-            ));
+            );
+            match File::create(SAVED_CANVAS_PATH) {
+                Err(e) => println!("Failed to create {0}: {1}", SAVED_CANVAS_PATH, e),
+                Ok(mut f) => {
+                    if let Err(e) = state.write_to(&mut f) {
+                        println!("Failed to persist the canvas: {0}", e);
+                    }
+                }
+            };
+            let mut hist = SAVED_CANVAS.lock().unwrap();
+            *hist = Some(state);
         }
     };
     end_bench!(save_canvas);
@@ -159,10 +173,7 @@ fn on_zoom_out(app: &mut appctx::ApplicationContext, _element: UIElementHandle)
             framebuffer.partial_refresh(
                 &CANVAS_REGION,
                 PartialRefreshMode::Async,
-                waveform_mode::WAVEFORM_MODE_GC16_FAST,
-                display_temp::TEMP_USE_REMARKABLE_DRAW,
-                dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
-                0,
+                &RefreshProfile::UI,
                 false,
             );
         }
@@ -191,10 +202,7 @@ fn on_blur_canvas(app: &mut appctx::ApplicationContext, _element: UIElementHandl
             framebuffer.partial_refresh(
                 &CANVAS_REGION,
                 PartialRefreshMode::Async,
-                waveform_mode::WAVEFORM_MODE_GC16_FAST,
-                display_temp::TEMP_USE_REMARKABLE_DRAW,
-                dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
-                0,
+                &RefreshProfile::UI,
                 false,
             );
         }
@@ -202,44 +210,42 @@ fn on_blur_canvas(app: &mut appctx::ApplicationContext, _element: UIElementHandl
     end_bench!(blur_canvas);
 }
 
-fn on_invert_canvas(app: &mut appctx::ApplicationContext, element: UIElementHandle) {
+fn on_toggle_night_mode(app: &mut appctx::ApplicationContext, _element: UIElementHandle) {
     start_bench!(stopwatch, invert);
+    // The EPDC inverts the updates itself, the framebuffer contents stay the same
     let framebuffer = app.get_framebuffer_ref();
-    match framebuffer.dump_region(CANVAS_REGION) {
-        Err(err) => println!("Failed to dump buffer: {0}", err),
-        Ok(mut buff) => {
-            buff.iter_mut().for_each(|p| {
-                *p = !(*p);
-            });
-            match framebuffer.restore_region(CANVAS_REGION, &buff) {
-                Err(e) => println!("Error while restoring region: {0}", e),
-                Ok(_) => {
-                    framebuffer.partial_refresh(
-                        &CANVAS_REGION,
-                        PartialRefreshMode::Async,
-                        waveform_mode::WAVEFORM_MODE_GC16_FAST,
-                        display_temp::TEMP_USE_REMARKABLE_DRAW,
-                        dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
-                        0,
-                        false,
-                    );
-                }
-            };
-        }
-    };
+    framebuffer.set_night_mode(!framebuffer.night_mode());
+    framebuffer.full_refresh(&RefreshProfile::CLEAN, false);
     end_bench!(invert);
-
-    // Invert the draw color as well for more natural UX
-    on_toggle_eraser(app, element);
 }
 
 fn on_load_canvas(app: &mut appctx::ApplicationContext, _element: UIElementHandle) {
     start_bench!(stopwatch, load_canvas);
-    match *SAVED_CANVAS.lock().unwrap() {
+    let mut saved = SAVED_CANVAS.lock().unwrap();
+    if saved.is_none() {
+        // Nothing saved during this run, try the one persisted by a previous run
+        *saved = match File::open(SAVED_CANVAS_PATH) {
+            Err(_) => None,
+            Ok(mut f) => match storage::CompressedCanvasState::read_from(&mut f) {
+                Err(e) => {
+                    println!("Ignoring {0}: {1}", SAVED_CANVAS_PATH, e);
+                    None
+                }
+                Ok(state) => Some(state),
+            },
+        };
+    }
+    match *saved {
         None => {}
         Some(ref compressed_state) => {
             let framebuffer = app.get_framebuffer_ref();
-            let decompressed = compressed_state.decompress();
+            let decompressed = match compressed_state.try_decompress() {
+                Ok(decompressed) => decompressed,
+                Err(e) => {
+                    println!("Error while decompressing the saved canvas: {0}", e);
+                    return;
+                }
+            };
 
             match framebuffer.restore_region(CANVAS_REGION, &decompressed) {
                 Err(e) => println!("Error while restoring region: {0}", e),
@@ -247,10 +253,7 @@ fn on_load_canvas(app: &mut appctx::ApplicationContext, _element: UIElementHandl
                     framebuffer.partial_refresh(
                         &CANVAS_REGION,
                         PartialRefreshMode::Async,
-                        waveform_mode::WAVEFORM_MODE_GC16_FAST,
-                        display_temp::TEMP_USE_REMARKABLE_DRAW,
-                        dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
-                        0,
+                        &RefreshProfile::UI,
                         false,
                     );
                 }
@@ -289,10 +292,12 @@ fn on_touch_rustlogo(app: &mut appctx::ApplicationContext, _element: UIElementHa
     framebuffer.partial_refresh(
         &rect,
         PartialRefreshMode::Wait,
-        waveform,
-        display_temp::TEMP_USE_MAX,
-        dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
-        0,
+        &RefreshProfile::new(
+            waveform,
+            TemperatureMode::Fixed(display_temp::TEMP_USE_MAX),
+            dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
+            0,
+        ),
         false,
     );
 }
@@ -339,10 +344,7 @@ fn draw_color_test_rgb(app: &mut appctx::ApplicationContext, _element: UIElement
     fb.partial_refresh(
         &CANVAS_REGION,
         PartialRefreshMode::Wait,
-        waveform_mode::WAVEFORM_MODE_GC16,
-        display_temp::TEMP_USE_PAPYRUS,
-        dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
-        0,
+        &RefreshProfile::IMAGE,
         false,
     );
 }
@@ -426,29 +428,14 @@ fn on_wacom_input(app: &mut appctx::ApplicationContext, input: wacom::WacomEvent
                 let framebuffer = app.get_framebuffer_ref();
                 let controlpt = wacom_stack.pop().unwrap();
                 let beginpt = wacom_stack.pop().unwrap();
-                let rect = framebuffer.draw_bezier(
+                framebuffer.draw_bezier(
                     beginpt.cast().unwrap(),
                     controlpt.cast().unwrap(),
                     position.cast().unwrap(),
                     rad,
                     col,
                 );
-
-                if !LAST_REFRESHED_CANVAS_RECT
-                    .load(Ordering::Relaxed)
-                    .contains_rect(&rect)
-                {
-                    framebuffer.partial_refresh(
-                        &rect,
-                        PartialRefreshMode::Async,
-                        waveform_mode::WAVEFORM_MODE_DU,
-                        display_temp::TEMP_USE_REMARKABLE_DRAW,
-                        dither_mode::EPDC_FLAG_EXP1,
-                        DRAWING_QUANT_BIT,
-                        false,
-                    );
-                    LAST_REFRESHED_CANVAS_RECT.store(rect, Ordering::Relaxed);
-                }
+                framebuffer.flush_damage(&RefreshProfile::INK);
             }
             wacom_stack.push(position.cast().unwrap());
         }
@@ -513,10 +500,10 @@ fn on_touch_handler(app: &mut appctx::ApplicationContext, input: multitouch::Mul
             framebuffer.partial_refresh(
                 &rect,
                 PartialRefreshMode::Async,
-                waveform_mode::WAVEFORM_MODE_DU,
-                display_temp::TEMP_USE_REMARKABLE_DRAW,
-                dither_mode::EPDC_FLAG_USE_DITHERING_ALPHA,
-                DRAWING_QUANT_BIT,
+                &RefreshProfile {
+                    dither_mode: dither_mode::EPDC_FLAG_USE_DITHERING_ALPHA,
+                    ..RefreshProfile::INK
+                },
                 false,
             );
         }
@@ -576,6 +563,10 @@ fn on_button_press(app: &mut appctx::ApplicationContext, input: gpio::GPIOEvent)
             app.draw_elements();
         }
         gpio::PhysicalButton::POWER => {
+            // The framebuffer doesn't get dropped on exit
+            if let Err(e) = app.get_framebuffer_ref().restore() {
+                println!("Failed to restore the framebuffer: {0}", e);
+            }
             Command::new("systemctl")
                 .arg("start")
                 .arg("xochitl")
@@ -600,12 +591,20 @@ fn main() {
     // Alternatively we could have called `app.execute_lua("fb.clear()")`
     app.clear(true);
 
+    // Clean up the ghosting the DU refreshes of the strokes leave behind
+    app.get_framebuffer_ref()
+        .scheduler
+        .set_config(SchedulerConfig {
+            enabled: true,
+            ..Default::default()
+        });
+
     // A rudimentary way to declare a scene and layout
     app.add_element(
         "logo",
         UIElementWrapper {
             position: cgmath::Point2 { x: 900, y: 10 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
 
             /* We could have alternatively done this:
 
@@ -625,7 +624,7 @@ fn main() {
         "canvasRegion",
         UIElementWrapper {
             position: CANVAS_REGION.top_left().cast().unwrap() + cgmath::vec2(0, -2),
-            refresh: UIConstraintRefresh::RefreshAndWait,
+            refresh: UIConstraintRefresh::RefreshAndWait(RefreshProfile::UI),
             onclick: None,
             inner: UIElement::Region {
                 size: CANVAS_REGION.size().cast().unwrap() + cgmath::vec2(1, 3),
@@ -640,7 +639,7 @@ fn main() {
         "colortest-rgb",
         UIElementWrapper {
             position: cgmath::Point2 { x: 960, y: 300 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
 
             onclick: Some(draw_color_test_rgb),
             inner: UIElement::Text {
@@ -658,7 +657,7 @@ fn main() {
         "zoomoutButton",
         UIElementWrapper {
             position: cgmath::Point2 { x: 960, y: 370 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
 
             onclick: Some(on_zoom_out),
             inner: UIElement::Text {
@@ -675,7 +674,7 @@ fn main() {
         "blurToggle",
         UIElementWrapper {
             position: cgmath::Point2 { x: 1155, y: 370 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
 
             onclick: Some(on_blur_canvas),
             inner: UIElement::Text {
@@ -692,9 +691,9 @@ fn main() {
         "invertToggle",
         UIElementWrapper {
             position: cgmath::Point2 { x: 1247, y: 370 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
 
-            onclick: Some(on_invert_canvas),
+            onclick: Some(on_toggle_night_mode),
             inner: UIElement::Text {
                 foreground: color::BLACK,
                 text: "Invert".to_owned(),
@@ -710,7 +709,7 @@ fn main() {
         "saveButton",
         UIElementWrapper {
             position: cgmath::Point2 { x: 960, y: 440 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
 
             onclick: Some(on_save_canvas),
             inner: UIElement::Text {
@@ -727,7 +726,7 @@ fn main() {
         "restoreButton",
         UIElementWrapper {
             position: cgmath::Point2 { x: 1080, y: 440 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
 
             onclick: Some(on_load_canvas),
             inner: UIElement::Text {
@@ -745,7 +744,7 @@ fn main() {
         "touchMode",
         UIElementWrapper {
             position: cgmath::Point2 { x: 960, y: 510 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
 
             onclick: Some(on_change_touchdraw_mode),
             inner: UIElement::Text {
@@ -761,7 +760,7 @@ fn main() {
         "touchModeIndicator",
         UIElementWrapper {
             position: cgmath::Point2 { x: 1210, y: 510 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
 
             onclick: None,
             inner: UIElement::Text {
@@ -779,7 +778,7 @@ fn main() {
         "colorToggle",
         UIElementWrapper {
             position: cgmath::Point2 { x: 960, y: 580 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
 
             onclick: Some(on_toggle_eraser),
             inner: UIElement::Text {
@@ -795,7 +794,7 @@ fn main() {
         "colorIndicator",
         UIElementWrapper {
             position: cgmath::Point2 { x: 1210, y: 580 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
 
             onclick: None,
             inner: UIElement::Text {
@@ -813,7 +812,7 @@ fn main() {
         "decreaseSize",
         UIElementWrapper {
             position: cgmath::Point2 { x: 960, y: 670 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
             onclick: Some(|appctx, _| {
                 change_brush_width(appctx, -1);
             }),
@@ -830,7 +829,7 @@ fn main() {
         "displaySize",
         UIElementWrapper {
             position: cgmath::Point2 { x: 1030, y: 670 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
             inner: UIElement::Text {
                 foreground: color::BLACK,
                 text: format!("size: {0}", G_DRAW_MODE.load(Ordering::Relaxed).get_size()),
@@ -844,7 +843,7 @@ fn main() {
         "increaseSize",
         UIElementWrapper {
             position: cgmath::Point2 { x: 1210, y: 670 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
             onclick: Some(|appctx, _| {
                 change_brush_width(appctx, 1);
             }),
@@ -862,7 +861,7 @@ fn main() {
         "exitToXochitl",
         UIElementWrapper {
             position: cgmath::Point2 { x: 30, y: 50 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
 
             onclick: None,
             inner: UIElement::Text {
@@ -878,7 +877,7 @@ fn main() {
         "availAt",
         UIElementWrapper {
             position: cgmath::Point2 { x: 30, y: 620 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
             inner: UIElement::Text {
                 foreground: color::BLACK,
                 text: "Available at:".to_owned(),
@@ -892,7 +891,7 @@ fn main() {
         "github",
         UIElementWrapper {
             position: cgmath::Point2 { x: 30, y: 690 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
             inner: UIElement::Text {
                 foreground: color::BLACK,
                 text: "github.com/canselcik/libremarkable".to_owned(),
@@ -906,7 +905,7 @@ fn main() {
         "l1",
         UIElementWrapper {
             position: cgmath::Point2 { x: 30, y: 350 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
             inner: UIElement::Text {
                 foreground: color::BLACK,
                 text: "Low Latency eInk Display Partial Refresh API".to_owned(),
@@ -920,7 +919,7 @@ fn main() {
         "l3",
         UIElementWrapper {
             position: cgmath::Point2 { x: 30, y: 400 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
             inner: UIElement::Text {
                 foreground: color::BLACK,
                 text: "Capacitive Multitouch Input Support".to_owned(),
@@ -934,7 +933,7 @@ fn main() {
         "l2",
         UIElementWrapper {
             position: cgmath::Point2 { x: 30, y: 450 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
             inner: UIElement::Text {
                 foreground: color::BLACK,
                 text: "Physical Button Support".to_owned(),
@@ -948,7 +947,7 @@ fn main() {
         "l4",
         UIElementWrapper {
             position: cgmath::Point2 { x: 30, y: 500 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
             inner: UIElement::Text {
                 foreground: color::BLACK,
                 text: "Wacom Digitizer Support".to_owned(),
@@ -963,7 +962,7 @@ fn main() {
         "tooltipLeft",
         UIElementWrapper {
             position: cgmath::Point2 { x: 15, y: 1850 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
             onclick: None,
             inner: UIElement::Text {
                 foreground: color::BLACK,
@@ -978,7 +977,7 @@ fn main() {
         "tooltipMiddle",
         UIElementWrapper {
             position: cgmath::Point2 { x: 565, y: 1850 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
             inner: UIElement::Text {
                 foreground: color::BLACK,
                 text: "Full Redraw".to_owned(),
@@ -992,7 +991,7 @@ fn main() {
         "tooltipRight",
         UIElementWrapper {
             position: cgmath::Point2 { x: 1112, y: 1850 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
             inner: UIElement::Text {
                 foreground: color::BLACK,
                 text: "Disable Touch".to_owned(),
@@ -1009,7 +1008,7 @@ fn main() {
         "battery",
         UIElementWrapper {
             position: cgmath::Point2 { x: 30, y: 215 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
             inner: UIElement::Text {
                 foreground: color::BLACK,
                 text: format!(
@@ -1030,7 +1029,7 @@ fn main() {
         "time",
         UIElementWrapper {
             position: cgmath::Point2 { x: 30, y: 150 },
-            refresh: UIConstraintRefresh::Refresh,
+            refresh: UIConstraintRefresh::Refresh(RefreshProfile::UI),
             inner: UIElement::Text {
                 foreground: color::BLACK,
                 text: format!("{}", dt.format("%F %r")),
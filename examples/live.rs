@@ -3,9 +3,8 @@ extern crate tiny_http;
 
 use libremarkable::framebuffer;
 use libremarkable::framebuffer::core::Framebuffer;
+use libremarkable::framebuffer::io::ScreenshotFormat;
 use libremarkable::framebuffer::{FramebufferBase, FramebufferIO};
-use libremarkable::image;
-use std::io::BufWriter;
 use tiny_http::{Response, Server};
 
 use libremarkable::framebuffer::common::{DISPLAYHEIGHT, DISPLAYWIDTH};
@@ -25,29 +24,18 @@ fn main() {
             continue;
         }
 
-        let rgb565 = fb
-            .dump_region(framebuffer::common::mxcfb_rect {
+        let mut jpg = Vec::new();
+        fb.screenshot(
+            framebuffer::common::mxcfb_rect {
                 top: 0,
                 left: 0,
                 width: DISPLAYWIDTH as u32,
                 height: DISPLAYHEIGHT as u32,
-            }).unwrap();
-
-        let rgb888 = framebuffer::storage::rgbimage_from_u8_slice(
-            DISPLAYWIDTH.into(),
-            DISPLAYHEIGHT.into(),
-            &rgb565,
+            },
+            ScreenshotFormat::JPEG { quality: 75 },
+            &mut jpg,
         ).unwrap();
-        let mut writer = BufWriter::new(Vec::new());
-        image::jpeg::JPEGEncoder::new(&mut writer)
-            .encode(
-                &*rgb888,
-                DISPLAYWIDTH.into(),
-                DISPLAYHEIGHT.into(),
-                image::ColorType::RGB(8),
-            ).unwrap();
 
-        let jpg = writer.into_inner().unwrap();
         let mut response = Response::new_empty(tiny_http::StatusCode(200))
             .with_data(&*jpg, Some(jpg.len()))
             .with_header(
@@ -0,0 +1,81 @@
+extern crate libremarkable;
+
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::common::{color, mxcfb_rect};
+use libremarkable::framebuffer::core::Framebuffer;
+use libremarkable::framebuffer::refresh::{PartialRefreshMode, RefreshProfile};
+use libremarkable::framebuffer::simulator::SimulatorDevice;
+use libremarkable::framebuffer::{FramebufferDraw, FramebufferRefresh};
+
+/// Renders a few updates on the simulated panel and writes the frames into the directory
+/// given as the only argument, defaulting to `frames`. Shows how DU, GC16_FAST and GC16
+/// render the same grays, the ghosting partial updates leave behind and a full update
+/// clearing it.
+fn main() {
+    let dir = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "frames".to_owned());
+    let device = match SimulatorDevice::with_output(&dir) {
+        Ok(device) => device,
+        Err(e) => {
+            eprintln!("{}: {}", dir, e);
+            std::process::exit(1);
+        }
+    };
+    let panel = device.panel();
+    let mut fb = Framebuffer::from_device(Box::new(device));
+    fb.clear();
+    fb.full_refresh(&RefreshProfile::CLEAN, true);
+
+    for (i, &(name, profile)) in [
+        ("DU", RefreshProfile::INK),
+        ("GC16_FAST", RefreshProfile::UI),
+        ("GC16", RefreshProfile::IMAGE),
+    ]
+    .iter()
+    .enumerate()
+    {
+        let y = 200.0 + i as f32 * 300.0;
+        for shade in 0..4 {
+            fb.fill_rect(
+                cgmath::Point2 {
+                    x: 100 + shade * 250,
+                    y: y as i32 + 40,
+                },
+                cgmath::Vector2 { x: 200, y: 150 },
+                color::GRAY(shade as u8 * 80),
+            );
+        }
+        let text = fb.draw_text(
+            cgmath::Point2 { x: 100.0, y },
+            name.to_owned(),
+            50.0,
+            color::BLACK,
+            false,
+        );
+        fb.partial_refresh(&text, PartialRefreshMode::Wait, &profile, false);
+        let row = mxcfb_rect {
+            top: y as u32 + 40,
+            left: 100,
+            width: 950,
+            height: 150,
+        };
+        fb.partial_refresh(&row, PartialRefreshMode::Wait, &profile, false);
+    }
+
+    // Erasing with partial updates leaves the ghosts of what was there behind
+    fb.clear();
+    fb.partial_refresh(
+        &mxcfb_rect {
+            top: 0,
+            left: 0,
+            width: fb.var_screen_info.xres,
+            height: fb.var_screen_info.yres,
+        },
+        PartialRefreshMode::Wait,
+        &RefreshProfile::UI,
+        false,
+    );
+    fb.full_refresh(&RefreshProfile::IMAGE, true);
+    println!("Wrote {} frames into {}", panel.frame_count(), dir);
+}
@@ -0,0 +1,79 @@
+extern crate libremarkable;
+
+use libremarkable::framebuffer::common::waveform_mode;
+use libremarkable::framebuffer::waveform::{WaveformFile, DEFAULT_FRAME_RATE, GRAY_LEVELS};
+
+/// The modes the EPDC of the reMarkable indexes the waveform file with
+const MODE_NAMES: [(waveform_mode, &str); 5] = [
+    (waveform_mode::WAVEFORM_MODE_INIT, "INIT"),
+    (waveform_mode::WAVEFORM_MODE_DU, "DU"),
+    (waveform_mode::WAVEFORM_MODE_GC16, "GC16"),
+    (waveform_mode::WAVEFORM_MODE_GC16_FAST, "GC16_FAST"),
+    (waveform_mode::WAVEFORM_MODE_GLR16, "GLR16"),
+];
+
+/// Dumps the header, temperature ranges and per-mode timing of an EPDC waveform file.
+/// Takes the path of the file as its only argument, defaulting to the one the driver loads.
+fn main() {
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "/lib/firmware/epdc/epdc_ES103CS1.fw".to_owned());
+    let file = match WaveformFile::open(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+
+    let header = &file.header;
+    println!("{}", path);
+    println!("  wi: {:08x?}", header.wi);
+    println!(
+        "  xwia: {:06x} wmta: {:06x} fvsn: {} luts: {} eb: {:02x} sb: {:02x}",
+        header.xwia, header.wmta, header.fvsn, header.luts, header.eb, header.sb
+    );
+    println!(
+        "  {} modes, {} temperature ranges",
+        header.mode_count(),
+        header.temperature_range_count()
+    );
+
+    print!("\n{:>12}", "");
+    for range in &file.temperature_ranges {
+        print!(" {:>4}-{:<4}", range.min, range.max);
+    }
+    println!();
+    for (index, mode) in file.modes.iter().enumerate() {
+        let name = MODE_NAMES
+            .iter()
+            .find(|&&(waveform, _)| waveform as usize == index)
+            .map_or("?", |&(_, name)| name);
+        print!("{:>2} {:<9}", index, name);
+        for lut in &mode.luts {
+            let duration = lut.duration(DEFAULT_FRAME_RATE);
+            let ms = duration.as_secs() * 1000 + u64::from(duration.subsec_millis());
+            print!(" {:>3}f {:>4}ms", lut.frame_count(), ms);
+        }
+        println!();
+    }
+
+    // The frames each gray level transition takes in the range room temperature falls into
+    let room = match file.temperature_index(24) {
+        Some(index) => index,
+        None => return,
+    };
+    for (index, mode) in file.modes.iter().enumerate() {
+        let lut = &mode.luts[room];
+        println!(
+            "\nmode {} at 24C, frames per transition (rows: from, columns: to)",
+            index
+        );
+        for from in 0..GRAY_LEVELS {
+            for to in 0..GRAY_LEVELS {
+                print!("{:>4}", lut.transition_frames(from, to));
+            }
+            println!();
+        }
+    }
+}
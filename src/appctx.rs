@@ -1,13 +1,18 @@
 use std;
 use std::cell::UnsafeCell;
 use std::ops::DerefMut;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::RwLock;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 use std::collections::HashMap;
 
 use image;
+use input::discovery;
 use input::ev;
+use input::recording::{InputRecorder, SharedRecorder};
 
 use framebuffer::common::*;
 
@@ -23,19 +28,23 @@ use aabb_quadtree::{geom, ItemId, QuadTree};
 
 use framebuffer::cgmath;
 use framebuffer::core;
-use framebuffer::refresh::PartialRefreshMode;
+use framebuffer::refresh::{PartialRefreshMode, RefreshProfile};
+use framebuffer::temperature::TemperatureMode;
 use framebuffer::FramebufferBase;
 use framebuffer::FramebufferDraw;
 use framebuffer::FramebufferRefresh;
 
 use input::gpio::GPIOEvent;
 use input::multitouch::MultitouchEvent;
-use input::wacom::WacomEvent;
+use input::wacom::{WacomEvent, WacomPen};
 use input::{InputDevice, InputEvent};
 
 #[cfg(feature = "enable-runtime-benchmarking")]
 use stopwatch;
 
+/// How often `dispatch_events(..)` checks for ghosting cleanup that is due, with or without input
+const REFRESH_SCHEDULER_POLL_INTERVAL_MS: u64 = 250;
+
 unsafe impl<'a> Send for ApplicationContext<'a> {}
 unsafe impl<'a> Sync for ApplicationContext<'a> {}
 
@@ -60,6 +69,14 @@ pub struct ApplicationContext<'a> {
     touch_ctx: RwLock<Option<ev::EvDevContext>>,
     on_touch: fn(&mut ApplicationContext, MultitouchEvent),
 
+    /// Used instead of discovering the input devices, see `set_input_device_path(..)`
+    input_device_paths: HashMap<InputDevice, PathBuf>,
+    /// The devices found in `/dev/input`, scanned for when the first device without a
+    /// path set is activated
+    discovered_input_devices: Option<HashMap<InputDevice, PathBuf>>,
+    /// Shared by the input devices when recording, see `record_input(..)`
+    input_recorder: SharedRecorder,
+
     active_regions: QuadTree<ActiveRegionHandler>,
     ui_elements: HashMap<String, UIElementHandle>,
 }
@@ -112,6 +129,9 @@ impl<'a> ApplicationContext<'a> {
             on_button,
             on_wacom,
             on_touch,
+            input_device_paths: HashMap::new(),
+            discovered_input_devices: None,
+            input_recorder: Arc::new(RwLock::new(None)),
             ui_elements: HashMap::new(),
             active_regions: QuadTree::default(geom::Rect::from_points(
                 &geom::Point { x: 0.0, y: 0.0 },
@@ -134,8 +154,8 @@ impl<'a> ApplicationContext<'a> {
         // Clears and refreshes the entire screen
         nms.set("clear", hlua::function0(luaext::lua_clear));
 
-        // Refreshes the provided rectangle. Here we are exposing a predefined set of the
-        // flags to the Lua API to simplify its use for building interfaces.
+        // Refreshes the provided rectangle. Here we are exposing the named `RefreshProfile`
+        // presets to the Lua API to simplify its use for building interfaces.
         nms.set("refresh", hlua::function6(luaext::lua_refresh));
 
         // Draws text with rusttype
@@ -179,20 +199,14 @@ impl<'a> ApplicationContext<'a> {
         }
 
         let marker = match refresh {
-            UIConstraintRefresh::Refresh | UIConstraintRefresh::RefreshAndWait => framebuffer
-                .partial_refresh(
-                    &draw_area,
-                    PartialRefreshMode::Async,
-                    waveform_mode::WAVEFORM_MODE_GC16_FAST,
-                    display_temp::TEMP_USE_REMARKABLE_DRAW,
-                    dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
-                    0,
-                    false,
-                ),
+            UIConstraintRefresh::Refresh(ref profile)
+            | UIConstraintRefresh::RefreshAndWait(ref profile) => {
+                framebuffer.partial_refresh(&draw_area, PartialRefreshMode::Async, profile, false)
+            }
             _ => return draw_area,
         };
 
-        if let UIConstraintRefresh::RefreshAndWait = refresh {
+        if let UIConstraintRefresh::RefreshAndWait(_) = refresh {
             framebuffer.wait_refresh_complete(marker);
         }
         draw_area.expand(border_px)
@@ -211,20 +225,14 @@ impl<'a> ApplicationContext<'a> {
         framebuffer.draw_rect(position, size, border_px as u32, border_color);
         let draw_area = mxcfb_rect::from(position.cast().unwrap(), size);
         let marker = match refresh {
-            UIConstraintRefresh::Refresh | UIConstraintRefresh::RefreshAndWait => framebuffer
-                .partial_refresh(
-                    &draw_area,
-                    PartialRefreshMode::Async,
-                    waveform_mode::WAVEFORM_MODE_GC16_FAST,
-                    display_temp::TEMP_USE_REMARKABLE_DRAW,
-                    dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
-                    0,
-                    false,
-                ),
+            UIConstraintRefresh::Refresh(ref profile)
+            | UIConstraintRefresh::RefreshAndWait(ref profile) => {
+                framebuffer.partial_refresh(&draw_area, PartialRefreshMode::Async, profile, false)
+            }
             _ => return draw_area,
         };
 
-        if let UIConstraintRefresh::RefreshAndWait = refresh {
+        if let UIConstraintRefresh::RefreshAndWait(_) = refresh {
             framebuffer.wait_refresh_complete(marker);
         }
         draw_area
@@ -242,20 +250,14 @@ impl<'a> ApplicationContext<'a> {
             other => framebuffer.draw_image(&other.to_rgb(), position),
         };
         let marker = match refresh {
-            UIConstraintRefresh::Refresh | UIConstraintRefresh::RefreshAndWait => framebuffer
-                .partial_refresh(
-                    &draw_area,
-                    PartialRefreshMode::Async,
-                    waveform_mode::WAVEFORM_MODE_GC16_FAST,
-                    display_temp::TEMP_USE_REMARKABLE_DRAW,
-                    dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
-                    0,
-                    false,
-                ),
+            UIConstraintRefresh::Refresh(ref profile)
+            | UIConstraintRefresh::RefreshAndWait(ref profile) => {
+                framebuffer.partial_refresh(&draw_area, PartialRefreshMode::Async, profile, false)
+            }
             _ => return draw_area,
         };
 
-        if let UIConstraintRefresh::RefreshAndWait = refresh {
+        if let UIConstraintRefresh::RefreshAndWait(_) = refresh {
             framebuffer.wait_refresh_complete(marker);
         }
         draw_area
@@ -337,10 +339,10 @@ impl<'a> ApplicationContext<'a> {
                 framebuffer.partial_refresh(
                     &rect,
                     PartialRefreshMode::Wait,
-                    waveform_mode::WAVEFORM_MODE_DU,
-                    display_temp::TEMP_USE_AMBIENT,
-                    dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
-                    0,
+                    &RefreshProfile {
+                        temperature: TemperatureMode::Fixed(display_temp::TEMP_USE_AMBIENT),
+                        ..RefreshProfile::MONOCHROME
+                    },
                     false,
                 );
 
@@ -360,13 +362,7 @@ impl<'a> ApplicationContext<'a> {
         framebuffer.clear();
 
         if deep {
-            framebuffer.full_refresh(
-                waveform_mode::WAVEFORM_MODE_INIT,
-                display_temp::TEMP_USE_AMBIENT,
-                dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
-                0,
-                true,
-            );
+            framebuffer.full_refresh(&RefreshProfile::CLEAN, true);
         } else {
             framebuffer.partial_refresh(
                 &mxcfb_rect {
@@ -376,10 +372,10 @@ impl<'a> ApplicationContext<'a> {
                     width: xres,
                 },
                 PartialRefreshMode::Wait,
-                waveform_mode::WAVEFORM_MODE_GC16_FAST,
-                display_temp::TEMP_USE_AMBIENT,
-                dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
-                0,
+                &RefreshProfile {
+                    temperature: TemperatureMode::Fixed(display_temp::TEMP_USE_AMBIENT),
+                    ..RefreshProfile::UI
+                },
                 false,
             );
         }
@@ -427,6 +423,20 @@ impl<'a> ApplicationContext<'a> {
         true
     }
 
+    /// Reads `t` from the evdev node at `path` instead of discovering it by scanning
+    /// `/dev/input`. Takes effect the next time the device is activated.
+    pub fn set_input_device_path(&mut self, t: InputDevice, path: PathBuf) {
+        self.input_device_paths.insert(t, path);
+    }
+
+    /// Records the raw events of the input devices into `recorder` until `None` is passed,
+    /// for replaying them later with `input::recording::replay(..)`. Takes effect right
+    /// away, also for the devices already active. Passing `None` finishes the recording
+    /// once the last `Arc` to the recorder is dropped.
+    pub fn record_input(&mut self, recorder: Option<Arc<InputRecorder>>) {
+        *self.input_recorder.write().unwrap() = recorder;
+    }
+
     /// Returns true if the device is now enabled. If it was enabled prior
     /// to calling this function, this function will return `true`.
     pub fn activate_input_device(&mut self, t: InputDevice) -> bool {
@@ -435,6 +445,19 @@ impl<'a> ApplicationContext<'a> {
             return true;
         }
 
+        let path = match self.input_device_paths.get(&t) {
+            Some(path) => path.clone(),
+            None => {
+                let discovered = self
+                    .discovered_input_devices
+                    .get_or_insert_with(discovery::scan);
+                match discovery::find(discovered, t) {
+                    Some(path) => path,
+                    None => return false,
+                }
+            }
+        };
+
         // Now we know it isn't active, let's create and spawn
         // the producer thread
         let mut dev = match t {
@@ -444,9 +467,10 @@ impl<'a> ApplicationContext<'a> {
             _ => return false,
         };
 
-        *dev = Some(ev::EvDevContext::new(t, self.input_tx.clone()));
+        *dev = Some(ev::EvDevContext::with_path(t, path, self.input_tx.clone()));
         match dev.as_mut() {
             Some(ref mut device) => {
+                device.set_recorder(Some(self.input_recorder.clone()));
                 device.start();
                 true
             }
@@ -491,9 +515,12 @@ impl<'a> ApplicationContext<'a> {
         // Now we consume the input events
         self.running.store(true, Ordering::Relaxed);
 
+        let poll_interval = Duration::from_millis(REFRESH_SCHEDULER_POLL_INTERVAL_MS);
+        let mut last_cleanup_check = Instant::now();
         let mut last_active_region_gesture_id: i32 = -1;
         while self.running.load(Ordering::Relaxed) {
-            match self.input_rx.recv() {
+            match self.input_rx.recv_timeout(poll_interval) {
+                Err(RecvTimeoutError::Timeout) => {}
                 Err(e) => println!("Error in input event consumer: {0}", e),
                 Ok(event) => match event {
                     InputEvent::GPIO { event } => {
@@ -520,11 +547,27 @@ impl<'a> ApplicationContext<'a> {
                         (self.on_touch)(appref, event);
                     }
                     InputEvent::WacomEvent { event } => {
+                        // Hold off the ghosting cleanup while the pen is on the screen
+                        if let WacomEvent::InstrumentChange { pen, state } = event {
+                            let scheduler = &self.framebuffer.scheduler;
+                            match (pen, state) {
+                                (WacomPen::Touch, true) => scheduler.begin_stroke(),
+                                (WacomPen::Touch, false) | (WacomPen::ToolPen, false) => {
+                                    scheduler.end_stroke()
+                                }
+                                _ => {}
+                            }
+                        }
                         (self.on_wacom)(appref, event);
                     }
                     _ => {}
                 },
             };
+            // Throttled so that a stream of pen samples doesn't check on every event
+            if last_cleanup_check.elapsed() >= poll_interval {
+                self.framebuffer.run_scheduled_cleanup();
+                last_cleanup_check = Instant::now();
+            }
         }
     }
 
@@ -0,0 +1,262 @@
+#![allow(non_camel_case_types)]
+
+use std::ptr;
+use std::slice;
+
+/// Number of entries of the gray lookup table, one for each 8-bit gray level
+pub const GRAY_LUT_SIZE: usize = 256;
+
+/// Struct as defined in /usr/include/linux/fb.h. The channels point into the buffers of
+/// the `Colormap` it is created from.
+#[repr(C)]
+pub(crate) struct fb_cmap {
+    pub start: u32,
+    pub len: u32,
+    pub red: *mut u16,
+    pub green: *mut u16,
+    pub blue: *mut u16,
+    pub transp: *mut u16,
+}
+
+/// A gray lookup table mapping every 8-bit gray level of the framebuffer to the one the
+/// PxP hands over to the EPDC
+pub type GrayLut = [u8; GRAY_LUT_SIZE];
+
+/// Leaves every gray level as is
+pub fn identity_lut() -> GrayLut {
+    let mut lut = [0u8; GRAY_LUT_SIZE];
+    for (i, v) in lut.iter_mut().enumerate() {
+        *v = i as u8;
+    }
+    lut
+}
+
+/// Stretches the gray levels between `black` and `white` over the whole range, clipping
+/// those outside of it. Brings out the contrast of faded scans.
+pub fn contrast_lut(black: u8, white: u8) -> GrayLut {
+    if black >= white {
+        return identity_lut();
+    }
+    let mut lut = [0u8; GRAY_LUT_SIZE];
+    let span = u32::from(white - black);
+    for (i, v) in lut.iter_mut().enumerate() {
+        *v = if i <= black as usize {
+            0
+        } else if i >= white as usize {
+            255
+        } else {
+            ((i as u32 - u32::from(black)) * 255 / span) as u8
+        };
+    }
+    lut
+}
+
+/// Applies a gamma curve, values above 1.0 darkening the midtones
+pub fn gamma_lut(gamma: f32) -> GrayLut {
+    let mut lut = [0u8; GRAY_LUT_SIZE];
+    for (i, v) in lut.iter_mut().enumerate() {
+        *v = ((i as f32 / 255.0).powf(gamma) * 255.0).round() as u8;
+    }
+    lut
+}
+
+/// Reduces the gray levels to `levels` evenly spaced ones, e.g. 2 for pure black and white
+/// line art or 4 for comics
+pub fn posterize_lut(levels: u8) -> GrayLut {
+    if levels < 2 {
+        return [0u8; GRAY_LUT_SIZE];
+    }
+    let steps = u32::from(levels) - 1;
+    let mut lut = [0u8; GRAY_LUT_SIZE];
+    for (i, v) in lut.iter_mut().enumerate() {
+        let step = (i as u32 * steps + 127) / 255;
+        *v = (step * 255 / steps) as u8;
+    }
+    lut
+}
+
+/// A colormap as read and written with `FBIOGETCMAP` and `FBIOPUTCMAP`, holding the
+/// 16-bit intensities of the entries `start..start + len()`.
+///
+/// In the 8-bit grayscale mode of the EPDC, the colormap is what the PxP remaps the gray
+/// levels with for updates that have the `USE_CMAP` flag, see `RefreshRequest::with_colormap(..)`.
+/// The PxP ignores it in any other mode, including the default 16-bit RGB565 one.
+/// `Colormap::from_gray_lut(..)` builds one from a `GrayLut`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Colormap {
+    pub start: u32,
+    pub red: Vec<u16>,
+    pub green: Vec<u16>,
+    pub blue: Vec<u16>,
+    pub transp: Option<Vec<u16>>,
+}
+
+impl Colormap {
+    /// A colormap of `len` black entries starting at `start`
+    pub fn new(start: u32, len: usize) -> Colormap {
+        Colormap {
+            start,
+            red: vec![0; len],
+            green: vec![0; len],
+            blue: vec![0; len],
+            transp: None,
+        }
+    }
+
+    /// Sets every channel of entry `i` to gray level `v`. Repeating the byte gives the same
+    /// value whether the driver takes the high or the low byte of the intensity.
+    pub fn from_gray_lut(lut: &GrayLut) -> Colormap {
+        let channel: Vec<u16> = lut.iter().map(|&v| u16::from(v) * 0x0101).collect();
+        Colormap {
+            start: 0,
+            red: channel.clone(),
+            green: channel.clone(),
+            blue: channel,
+            transp: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.red.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.red.is_empty()
+    }
+
+    /// Returns the gray levels of the red channel, `None` unless the colormap covers all
+    /// of them
+    pub fn gray_lut(&self) -> Option<GrayLut> {
+        if self.start != 0 || self.len() < GRAY_LUT_SIZE {
+            return None;
+        }
+        let mut lut = [0u8; GRAY_LUT_SIZE];
+        for (v, &intensity) in lut.iter_mut().zip(self.red.iter()) {
+            *v = (intensity >> 8) as u8;
+        }
+        Some(lut)
+    }
+
+    /// Points an `fb_cmap` at the channels, which all need to be `len()` long. The result
+    /// is only valid until the channels get resized or dropped.
+    pub(crate) fn as_fb_cmap(&mut self) -> Result<fb_cmap, &'static str> {
+        let len = self.len();
+        if self.green.len() != len
+            || self.blue.len() != len
+            || self.transp.as_ref().map_or(false, |t| t.len() != len)
+        {
+            return Err("The channels of the colormap differ in length");
+        }
+        Ok(fb_cmap {
+            start: self.start,
+            len: len as u32,
+            red: self.red.as_mut_ptr(),
+            green: self.green.as_mut_ptr(),
+            blue: self.blue.as_mut_ptr(),
+            transp: match self.transp {
+                Some(ref mut transp) => transp.as_mut_ptr(),
+                None => ptr::null_mut(),
+            },
+        })
+    }
+
+    /// Copies the channels `cmap` points to, `transp` being optional.
+    ///
+    /// # Safety
+    ///
+    /// The channels of `cmap` need to be valid for reading `cmap.len` entries.
+    pub(crate) unsafe fn from_fb_cmap(cmap: &fb_cmap) -> Colormap {
+        let len = cmap.len as usize;
+        let channel = |ptr: *mut u16| slice::from_raw_parts(ptr as *const u16, len).to_vec();
+        Colormap {
+            start: cmap.start,
+            red: channel(cmap.red),
+            green: channel(cmap.green),
+            blue: channel(cmap.blue),
+            transp: if cmap.transp.is_null() {
+                None
+            } else {
+                Some(channel(cmap.transp))
+            },
+        }
+    }
+
+    /// Copies the channels into the ones `cmap` points to. Fails unless `cmap` covers the
+    /// same entries and has a `transp` channel exactly if the colormap does.
+    ///
+    /// # Safety
+    ///
+    /// The channels of `cmap` need to be valid for writing `cmap.len` entries.
+    pub(crate) unsafe fn copy_to_fb_cmap(&self, cmap: &fb_cmap) -> Result<(), &'static str> {
+        if cmap.start != self.start
+            || cmap.len as usize != self.len()
+            || cmap.transp.is_null() != self.transp.is_none()
+        {
+            return Err("The colormap doesn't cover the entries of the fb_cmap");
+        }
+        let len = self.len();
+        ptr::copy_nonoverlapping(self.red.as_ptr(), cmap.red, len);
+        ptr::copy_nonoverlapping(self.green.as_ptr(), cmap.green, len);
+        ptr::copy_nonoverlapping(self.blue.as_ptr(), cmap.blue, len);
+        if let Some(ref transp) = self.transp {
+            ptr::copy_nonoverlapping(transp.as_ptr(), cmap.transp, len);
+        }
+        Ok(())
+    }
+
+    /// Serializes the colormap for traces, which can't keep the pointers of an `fb_cmap`:
+    /// `start` and `len()` as `u32`, a byte telling whether `transp` follows, then the
+    /// intensities of each channel, all little endian.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let channels = if self.transp.is_some() { 4 } else { 3 };
+        let mut bytes = Vec::with_capacity(9 + channels * 2 * self.len());
+        bytes.extend_from_slice(&self.start.to_le_bytes());
+        bytes.extend_from_slice(&(self.len() as u32).to_le_bytes());
+        bytes.push(self.transp.is_some() as u8);
+        let transp = self.transp.as_ref().map(|t| t.as_slice());
+        let channels = [
+            Some(&self.red[..]),
+            Some(&self.green[..]),
+            Some(&self.blue[..]),
+            transp,
+        ];
+        for channel in channels.iter().filter_map(|c| *c) {
+            for intensity in channel {
+                bytes.extend_from_slice(&intensity.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    /// Parses the bytes written by `to_bytes()`
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Colormap> {
+        if bytes.len() < 9 || bytes[8] > 1 {
+            return None;
+        }
+        let u32_at = |i: usize| {
+            let mut buf = [0u8; 4];
+            buf.copy_from_slice(&bytes[i..i + 4]);
+            u32::from_le_bytes(buf)
+        };
+        let start = u32_at(0);
+        let len = u32_at(4) as usize;
+        let has_transp = bytes[8] == 1;
+        let channels = if has_transp { 4 } else { 3 };
+        if bytes.len() != 9 + channels * 2 * len {
+            return None;
+        }
+        let channel = |n: usize| -> Vec<u16> {
+            bytes[9 + n * 2 * len..9 + (n + 1) * 2 * len]
+                .chunks(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect()
+        };
+        Some(Colormap {
+            start,
+            red: channel(0),
+            green: channel(1),
+            blue: channel(2),
+            transp: if has_transp { Some(channel(3)) } else { None },
+        })
+    }
+}
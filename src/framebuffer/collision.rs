@@ -0,0 +1,90 @@
+use std::sync::Mutex;
+
+use framebuffer::common::mxcfb_rect;
+
+/// Updates that are never waited for are forgotten once this many more recent ones have
+/// been submitted. That is far more than the EPDC queues at once, so they have long completed.
+const MAX_IN_FLIGHT_UPDATES: usize = 64;
+
+/// What `submit_refresh(..)` does with a request whose region collides with updates that
+/// are still in flight, which would otherwise leave stale pixels where they overlap.
+///
+/// Collisions are detected by the driver: the request is first sent as a collision test
+/// (`EPDC_FLAG_TEST_COLLISION`) and its `collision_test` result is checked. The updates it
+/// collided with are then looked up among the ones submitted through the framebuffer.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CollisionPolicy {
+    /// Grow the region to cover the colliding updates as well and submit it once they have
+    /// completed, superseding them, so that the whole area ends up showing the latest contents
+    Merge,
+    /// Wait for the colliding updates to complete, then submit the request as it is
+    WaitThenSubmit,
+    /// Don't submit the request, `submit_refresh(..)` fails with `WouldBlock`
+    Drop,
+}
+
+fn overlaps(a: &mxcfb_rect, b: &mxcfb_rect) -> bool {
+    a.left < b.left + b.width
+        && b.left < a.left + a.width
+        && a.top < b.top + b.height
+        && b.top < a.top + a.height
+}
+
+struct InFlightUpdate {
+    marker: u32,
+    region: mxcfb_rect,
+}
+
+/// The updates that have been submitted to the EPDC and haven't been seen completing yet,
+/// which are the ones to wait for when the driver reports a collision. An update is seen
+/// completing when it is waited for, directly or through the `CompletionService`, and when
+/// the driver reports no collision for a region overlapping it. Updates that are never seen
+/// completing are only forgotten after `MAX_IN_FLIGHT_UPDATES` newer ones.
+#[derive(Default)]
+pub struct InFlightUpdates {
+    updates: Mutex<Vec<InFlightUpdate>>,
+}
+
+impl InFlightUpdates {
+    pub fn new() -> InFlightUpdates {
+        InFlightUpdates::default()
+    }
+
+    pub fn add(&self, marker: u32, region: mxcfb_rect) {
+        let mut updates = self.updates.lock().unwrap();
+        if updates.len() >= MAX_IN_FLIGHT_UPDATES {
+            updates.remove(0);
+        }
+        updates.push(InFlightUpdate { marker, region });
+    }
+
+    pub fn complete(&self, marker: u32) {
+        self.updates
+            .lock()
+            .unwrap()
+            .retain(|update| update.marker != marker);
+    }
+
+    /// Forgets the updates overlapping `region`, which the driver reported no collision with
+    pub fn complete_overlapping(&self, region: &mxcfb_rect) {
+        self.updates
+            .lock()
+            .unwrap()
+            .retain(|update| !overlaps(&update.region, region));
+    }
+
+    /// Returns the markers and regions of the in-flight updates overlapping `region`
+    pub fn colliding(&self, region: &mxcfb_rect) -> Vec<(u32, mxcfb_rect)> {
+        self.updates
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|update| overlaps(&update.region, region))
+            .map(|update| (update.marker, update.region))
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.updates.lock().unwrap().is_empty()
+    }
+}
@@ -28,9 +28,22 @@ pub const MXCFB_SEND_UPDATE: NativeWidthType =
     iow!(b'F', 0x2E, std::mem::size_of::<mxcfb_update_data>()) as NativeWidthType;
 pub const MXCFB_WAIT_FOR_UPDATE_COMPLETE: NativeWidthType =
     iowr!(b'F', 0x2F, std::mem::size_of::<mxcfb_update_marker_data>()) as NativeWidthType;
+pub const MXCFB_SET_WAVEFORM_MODES: NativeWidthType =
+    iow!(b'F', 0x2B, std::mem::size_of::<mxcfb_waveform_modes>()) as NativeWidthType;
+pub const MXCFB_SET_TEMPERATURE: NativeWidthType =
+    iow!(b'F', 0x2C, std::mem::size_of::<i32>()) as NativeWidthType;
+pub const MXCFB_SET_PWRDOWN_DELAY: NativeWidthType =
+    iow!(b'F', 0x30, std::mem::size_of::<i32>()) as NativeWidthType;
+pub const MXCFB_GET_PWRDOWN_DELAY: NativeWidthType =
+    ior!(b'F', 0x31, std::mem::size_of::<i32>()) as NativeWidthType;
+pub const MXCFB_GET_WORK_BUFFER: NativeWidthType =
+    iowr!(b'F', 0x34, std::mem::size_of::<std::os::raw::c_ulong>()) as NativeWidthType;
 pub const MXCFB_DISABLE_EPDC_ACCESS: NativeWidthType = io!(b'F', 0x35) as NativeWidthType;
 pub const MXCFB_ENABLE_EPDC_ACCESS: NativeWidthType = io!(b'F', 0x36) as NativeWidthType;
 
+/// Passed to MXCFB_SET_PWRDOWN_DELAY to keep the EPDC powered up between updates
+pub const FB_POWERDOWN_DISABLE: i32 = -1;
+
 pub const FBIOPUT_VSCREENINFO: NativeWidthType = 0x4601;
 pub const FBIOGET_VSCREENINFO: NativeWidthType = 0x4600;
 pub const FBIOGET_FSCREENINFO: NativeWidthType = 0x4602;
@@ -140,6 +153,18 @@ pub const EPDC_FLAG_USE_ALT_BUFFER: u32 = 0x0100;
 pub const EPDC_FLAG_TEST_COLLISION: u32 = 0x0200;
 pub const EPDC_FLAG_GROUP_UPDATE: u32 = 0x0400;
 
+bitflags! {
+    /// The `flags` of an `mxcfb_update_data`, see the `EPDC_FLAG_*` consts above
+    pub struct EpdcFlags: u32 {
+        const ENABLE_INVERSION = EPDC_FLAG_ENABLE_INVERSION;
+        const FORCE_MONOCHROME = EPDC_FLAG_FORCE_MONOCHROME;
+        const USE_CMAP = EPDC_FLAG_USE_CMAP;
+        const USE_ALT_BUFFER = EPDC_FLAG_USE_ALT_BUFFER;
+        const TEST_COLLISION = EPDC_FLAG_TEST_COLLISION;
+        const GROUP_UPDATE = EPDC_FLAG_GROUP_UPDATE;
+    }
+}
+
 /// xochitl tends to draw with these but there are many more
 pub const DRAWING_QUANT_BIT: i32 = 0x7614_3b24;
 pub const DRAWING_QUANT_BIT_2: i32 = 0x75e7_bb24;
@@ -291,20 +316,20 @@ pub enum mxcfb_ioctl {
     MXCFB_ENABLE_EPDC_ACCESS = 0x36,
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum auto_update_mode {
     AUTO_UPDATE_MODE_REGION_MODE = 0,
     AUTO_UPDATE_MODE_AUTOMATIC_MODE = 1,
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum update_scheme {
     UPDATE_SCHEME_SNAPSHOT = 0,
     UPDATE_SCHEME_QUEUE = 1,
     UPDATE_SCHEME_QUEUE_AND_MERGE = 2,
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum update_mode {
     /// Returns a marker, no locking, no waiting on the
     /// clean state on the update region
@@ -315,7 +340,7 @@ pub enum update_mode {
     UPDATE_MODE_FULL = 1,
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum dither_mode {
     EPDC_FLAG_USE_DITHERING_PASSTHROUGH = 0x0,
     EPDC_FLAG_USE_DITHERING_DRAWING = 0x1,
@@ -335,7 +360,7 @@ pub enum dither_mode {
     EPDC_FLAG_EXP8 = 0x7ed3_d2c0,
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum waveform_mode {
     /// (Recommended) Screen goes to white
     /// (flashes black/white once to clear ghosting when used with UPDATE_MODE_FULL)
@@ -386,7 +411,7 @@ pub enum waveform_mode {
     WAVEFORM_MODE_AUTO = 257,
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum display_temp {
     /// Seems to have the best draw latency. Perhaps the rule of thumb here is the lower the faster.
     /// `xochitl` seems to use this value.
@@ -0,0 +1,160 @@
+use futures::sync::oneshot;
+use futures::{Future, Poll};
+
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use framebuffer::collision::InFlightUpdates;
+use framebuffer::common::MXCFB_WAIT_FOR_UPDATE_COMPLETE;
+use framebuffer::device::{as_bytes, FramebufferDevice};
+use framebuffer::mxcfb::mxcfb_update_marker_data;
+use framebuffer::telemetry::Telemetry;
+
+/// A refresh that has been reflected on the display
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Completion {
+    pub marker: u32,
+    /// The `collision_test` result of the update, `None` if waiting for it failed
+    pub collision_test: Option<u32>,
+    /// Time between the marker being handed to the service and its completion
+    pub elapsed: Duration,
+}
+
+/// `Box<FnOnce>` can't be called, so the callbacks are boxed behind this instead
+trait Notify: Send {
+    fn notify(self: Box<Self>, completion: Completion);
+}
+
+impl<F: FnOnce(Completion) + Send> Notify for F {
+    fn notify(self: Box<Self>, completion: Completion) {
+        (*self)(completion)
+    }
+}
+
+struct Job {
+    marker: u32,
+    queued_at: Instant,
+    notify: Box<dyn Notify>,
+}
+
+/// Waits for the completion of refreshes on a background thread and notifies whoever is
+/// interested, so that the thread submitting them never blocks on `wait_refresh_complete(..)`.
+///
+/// Completions are delivered through a callback run on the service thread, a channel, or
+/// a `CompletionFuture`. Markers are waited for in the order they were handed over, so a
+/// refresh that completes early is reported once those handed over before it are done.
+/// The service thread is started along with the first marker and stops once the service
+/// is dropped.
+pub struct CompletionService {
+    device: Arc<dyn FramebufferDevice>,
+    telemetry: Arc<Telemetry>,
+    in_flight: Arc<InFlightUpdates>,
+    sender: Mutex<Option<mpsc::Sender<Job>>>,
+}
+
+impl CompletionService {
+    /// The completions are recorded into `telemetry` and `in_flight`
+    pub fn new(
+        device: Arc<dyn FramebufferDevice>,
+        telemetry: Arc<Telemetry>,
+        in_flight: Arc<InFlightUpdates>,
+    ) -> CompletionService {
+        CompletionService {
+            device,
+            telemetry,
+            in_flight,
+            sender: Mutex::new(None),
+        }
+    }
+
+    /// Calls `callback` on the service thread once the refresh tagged with `marker` completes.
+    /// The callback shouldn't block, as it holds up the completions that follow.
+    pub fn on_complete<F: FnOnce(Completion) + Send + 'static>(&self, marker: u32, callback: F) {
+        let job = Job {
+            marker,
+            queued_at: Instant::now(),
+            notify: Box::new(callback),
+        };
+        let mut sender = self.sender.lock().unwrap();
+        if sender.is_none() {
+            *sender = Some(self.spawn());
+        }
+        if let Err(mpsc::SendError(job)) = sender.as_ref().unwrap().send(job) {
+            // The service thread is gone, start over with a new one
+            let new_sender = self.spawn();
+            let _ = new_sender.send(job);
+            *sender = Some(new_sender);
+        }
+    }
+
+    /// Returns a channel that receives the completion of the refresh tagged with `marker`
+    pub fn channel(&self, marker: u32) -> mpsc::Receiver<Completion> {
+        let (tx, rx) = mpsc::channel();
+        self.on_complete(marker, move |completion| {
+            let _ = tx.send(completion);
+        });
+        rx
+    }
+
+    /// Returns a future that resolves to the completion of the refresh tagged with `marker`
+    pub fn future(&self, marker: u32) -> CompletionFuture {
+        let (tx, rx) = oneshot::channel();
+        self.on_complete(marker, move |completion| {
+            let _ = tx.send(completion);
+        });
+        CompletionFuture { receiver: rx }
+    }
+
+    fn spawn(&self) -> mpsc::Sender<Job> {
+        let (tx, rx) = mpsc::channel::<Job>();
+        let device = self.device.clone();
+        let telemetry = self.telemetry.clone();
+        let in_flight = self.in_flight.clone();
+        thread::spawn(move || {
+            for job in rx.iter() {
+                let mut markerdata = mxcfb_update_marker_data {
+                    update_marker: job.marker,
+                    collision_test: 0,
+                };
+                let result = unsafe {
+                    device.ioctl(MXCFB_WAIT_FOR_UPDATE_COMPLETE, as_bytes(&mut markerdata))
+                };
+                let collision_test = match result {
+                    Ok(()) => Some(markerdata.collision_test),
+                    Err(e) => {
+                        warn!(
+                            "WAIT_FOR_UPDATE_COMPLETE failed for marker {}: {}",
+                            job.marker, e
+                        );
+                        None
+                    }
+                };
+                in_flight.complete(job.marker);
+                telemetry.record_completion(job.marker, collision_test);
+                job.notify.notify(Completion {
+                    marker: job.marker,
+                    collision_test,
+                    elapsed: job.queued_at.elapsed(),
+                });
+            }
+        });
+        tx
+    }
+}
+
+/// Resolves to the `Completion` of a refresh, see `CompletionService::future(..)`.
+/// Fails with `Canceled` if the service thread went away before the refresh completed.
+pub struct CompletionFuture {
+    receiver: oneshot::Receiver<Completion>,
+}
+
+impl Future for CompletionFuture {
+    type Item = Completion;
+    type Error = oneshot::Canceled;
+
+    fn poll(&mut self) -> Poll<Completion, oneshot::Canceled> {
+        self.receiver.poll()
+    }
+}
@@ -1,27 +1,46 @@
 #![allow(dead_code)]
 
-use libc;
-use libc::ioctl;
-use mmap;
 use mmap::MemoryMap;
 
-use std::fs::{File, OpenOptions};
-use std::os::unix::io::AsRawFd;
-use std::sync::atomic::AtomicU32;
+use std::io;
+use std::os::raw::c_ulong;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use framebuffer;
+use framebuffer::cgmath;
+use framebuffer::cmap::{Colormap, GRAY_LUT_SIZE};
+use framebuffer::collision::InFlightUpdates;
 use framebuffer::common::{
-    FBIOGET_FSCREENINFO, FBIOGET_VSCREENINFO, FBIOPUT_VSCREENINFO, MXCFB_DISABLE_EPDC_ACCESS,
-    MXCFB_ENABLE_EPDC_ACCESS, MXCFB_SET_AUTO_UPDATE_MODE, MXCFB_SET_UPDATE_SCHEME,
+    auto_update_mode, display_temp, update_scheme, NativeWidthType, FBIOGETCMAP,
+    FBIOGET_FSCREENINFO, FBIOGET_VSCREENINFO, FBIOPAN_DISPLAY, FBIOPUTCMAP, FBIOPUT_VSCREENINFO,
+    FB_POWERDOWN_DISABLE, MXCFB_DISABLE_EPDC_ACCESS, MXCFB_ENABLE_EPDC_ACCESS,
+    MXCFB_GET_PWRDOWN_DELAY, MXCFB_GET_WORK_BUFFER, MXCFB_SET_AUTO_UPDATE_MODE,
+    MXCFB_SET_PWRDOWN_DELAY, MXCFB_SET_TEMPERATURE, MXCFB_SET_UPDATE_SCHEME,
+    MXCFB_SET_WAVEFORM_MODES,
 };
+use framebuffer::completion::CompletionService;
+use framebuffer::damage::{DamageTracker, DEFAULT_MERGE_DISTANCE};
+use framebuffer::device::{as_bytes, check_arg_size, FramebufferDevice, LinuxDevice};
+use framebuffer::mxcfb::mxcfb_waveform_modes;
+use framebuffer::scheduler::{RefreshScheduler, SchedulerConfig};
 use framebuffer::screeninfo::{FixScreeninfo, VarScreeninfo};
+use framebuffer::state::{self, DriverState, DriverStateGuard, PinnedSettings};
+use framebuffer::telemetry::Telemetry;
+use framebuffer::temperature::TemperatureSensor;
+use framebuffer::FramebufferBase;
 
 use rusttype::{Font, FontCollection};
 
 /// Framebuffer struct containing the state (latest update marker etc.)
 /// along with the var/fix screeninfo structs.
+///
+/// Dropping it puts back the var screeninfo it found and the driver settings changed
+/// through it, see `restore()`.
 pub struct Framebuffer<'a> {
-    pub device: File,
+    /// Every ioctl of the framebuffer goes through this device
+    pub device: Arc<dyn FramebufferDevice>,
     pub frame: MemoryMap,
     pub marker: AtomicU32,
     pub default_font: Font<'a>,
@@ -30,21 +49,51 @@ pub struct Framebuffer<'a> {
     /// like it has been done in `Framebuffer::new(..)`.
     pub var_screen_info: VarScreeninfo,
     pub fix_screen_info: FixScreeninfo,
+    /// The regions drawn over since they were last refreshed
+    pub damage: DamageTracker,
+    /// Schedules the cleanup of the ghosting left behind by fast partial refreshes
+    pub scheduler: RefreshScheduler,
+    /// Measures the temperature refreshes using `TemperatureMode::Auto` are performed for
+    pub temperature: TemperatureSensor,
+    /// Notifies of the completion of refreshes without blocking
+    pub completions: CompletionService,
+    /// Latency and size metrics of the refreshes
+    pub telemetry: Arc<Telemetry>,
+    /// The updates submitted to the EPDC that haven't completed yet
+    pub in_flight: Arc<InFlightUpdates>,
+    /// Whether every update is displayed inverted, see `FramebufferRefresh::set_night_mode(..)`
+    pub(crate) night_mode: AtomicBool,
+    /// Put back by `restore()`, along with the default `DriverState`
+    original_var_screen_info: VarScreeninfo,
+    driver_state: Mutex<DriverState>,
+    pinned: Mutex<PinnedSettings>,
+    /// Number of screen sized pages mapped, see `Framebuffer::from_device_paged(..)`
+    pages: u32,
+    draw_page: AtomicU32,
+    displayed_page: AtomicU32,
 }
 
 unsafe impl<'a> Send for Framebuffer<'a> {}
 unsafe impl<'a> Sync for Framebuffer<'a> {}
 
-impl<'a> framebuffer::FramebufferBase<'a> for Framebuffer<'a> {
-    fn new(path_to_device: &str) -> Framebuffer {
-        let device = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(path_to_device)
-            .unwrap();
-
-        let fix_screen_info = Framebuffer::get_fix_screeninfo(&device);
-        let mut var_screen_info = Framebuffer::get_var_screeninfo(&device);
+impl<'a> Framebuffer<'a> {
+    /// Creates a new instance of Framebuffer on top of `device`, which may be a
+    /// `device::TracingDevice` recording the ioctls or a `device::MockDevice` when
+    /// there is no display to drive.
+    pub fn from_device(device: Box<dyn FramebufferDevice>) -> Framebuffer<'a> {
+        Framebuffer::from_device_paged(device, 1)
+    }
+
+    /// Like `from_device(..)`, but sets up the virtual resolution to hold `pages` screens
+    /// stacked vertically and maps all of them. One page is displayed while the others can
+    /// be drawn to off-screen and panned to once ready, see `pan_to_page(..)`.
+    ///
+    /// Fewer pages are set up if the framebuffer memory can't hold that many, and a single
+    /// one if the driver rejects the virtual resolution, see `page_count()`.
+    pub fn from_device_paged(device: Box<dyn FramebufferDevice>, pages: u32) -> Framebuffer<'a> {
+        let device: Arc<dyn FramebufferDevice> = Arc::from(device);
+        let original_var_screen_info = Framebuffer::get_var_screeninfo(&*device);
+        let mut var_screen_info = original_var_screen_info.clone();
         var_screen_info.xres = 1872;
         var_screen_info.yres = 1404;
         var_screen_info.rotate = 1;
@@ -61,23 +110,52 @@ impl<'a> framebuffer::FramebufferBase<'a> for Framebuffer<'a> {
         var_screen_info.vmode = 0; // FB_VMODE_NONINTERLACED
         var_screen_info.accel_flags = 0;
 
-        Framebuffer::put_var_screeninfo(&device, &mut var_screen_info);
-
-        let frame_length = (fix_screen_info.line_length * var_screen_info.yres) as usize;
-        let mem_map = MemoryMap::new(
-            frame_length,
-            &[
-                mmap::MapOption::MapReadable,
-                mmap::MapOption::MapWritable,
-                mmap::MapOption::MapFd(device.as_raw_fd()),
-                mmap::MapOption::MapOffset(0),
-                mmap::MapOption::MapNonStandardFlags(libc::MAP_SHARED),
-            ],
-        ).unwrap();
+        // The rotation decides the resolution and line length, and with them how many pages
+        // the framebuffer memory holds, so only ask for more once it is applied
+        Framebuffer::put_var_screeninfo(&*device, &mut var_screen_info);
+        let mut fix_screen_info = Framebuffer::get_fix_screeninfo(&*device);
+        let page_length = fix_screen_info.line_length * var_screen_info.yres;
+        let max_pages = ::std::cmp::max(
+            fix_screen_info.smem_len / ::std::cmp::max(page_length, 1),
+            1,
+        );
+        let mut pages = ::std::cmp::min(::std::cmp::max(pages, 1), max_pages);
+        if pages > 1 {
+            let mut paged = var_screen_info.clone();
+            paged.yres_virtual = paged.yres * pages;
+            paged.yoffset = 0;
+            if Framebuffer::put_var_screeninfo(&*device, &mut paged) {
+                var_screen_info = paged;
+                fix_screen_info = Framebuffer::get_fix_screeninfo(&*device);
+                // The driver may have settled for a smaller virtual resolution
+                pages = ::std::cmp::max(
+                    ::std::cmp::min(pages, var_screen_info.yres_virtual / var_screen_info.yres),
+                    1,
+                );
+            } else {
+                warn!(
+                    "The driver rejected a virtual resolution of {} pages, using a single one",
+                    pages
+                );
+                pages = 1;
+            }
+        }
+        let frame_length = (fix_screen_info.line_length * var_screen_info.yres * pages) as usize;
+        let mem_map = device.map(frame_length).unwrap();
 
         // Load the font
         let font_data = include_bytes!("../../assets/Roboto-Regular.ttf");
         let collection = FontCollection::from_bytes(font_data as &[u8]);
+        let bounds = cgmath::Vector2 {
+            x: var_screen_info.xres,
+            y: var_screen_info.yres,
+        };
+        let damage = DamageTracker::new(bounds, DEFAULT_MERGE_DISTANCE);
+        let scheduler = RefreshScheduler::new(bounds, SchedulerConfig::default());
+        let telemetry = Arc::new(Telemetry::default());
+        let in_flight = Arc::new(InFlightUpdates::new());
+        let completions =
+            CompletionService::new(device.clone(), telemetry.clone(), in_flight.clone());
         Framebuffer {
             marker: AtomicU32::new(1),
             device,
@@ -85,64 +163,308 @@ impl<'a> framebuffer::FramebufferBase<'a> for Framebuffer<'a> {
             default_font: collection.into_font().unwrap(),
             var_screen_info,
             fix_screen_info,
+            damage,
+            scheduler,
+            temperature: TemperatureSensor::default(),
+            completions,
+            telemetry,
+            in_flight,
+            night_mode: AtomicBool::new(false),
+            original_var_screen_info,
+            driver_state: Mutex::new(DriverState::default()),
+            pinned: Mutex::new(PinnedSettings::default()),
+            pages,
+            draw_page: AtomicU32::new(0),
+            displayed_page: AtomicU32::new(0),
+        }
+    }
+
+    /// Number of screen sized pages mapped, which may be fewer than the ones asked for in
+    /// `from_device_paged(..)`
+    pub fn page_count(&self) -> u32 {
+        self.pages
+    }
+
+    /// The page the drawing and reading functions operate on, 0 unless changed with
+    /// `set_draw_page(..)`
+    pub fn draw_page(&self) -> u32 {
+        self.draw_page.load(Ordering::Relaxed)
+    }
+
+    /// Directs the drawing and reading functions, screenshots included, to `page`. The
+    /// damage is tracked in screen coordinates regardless of the page it was drawn to.
+    pub fn set_draw_page(&self, page: u32) -> Result<(), &'static str> {
+        if page >= self.pages {
+            return Err("The page lies outside of the mapped framebuffer");
+        }
+        self.draw_page.store(page, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// The page the EPDC refreshes the display from
+    pub fn displayed_page(&self) -> u32 {
+        self.displayed_page.load(Ordering::Relaxed)
+    }
+
+    /// Pans the display to `page` with `FBIOPAN_DISPLAY`. The panel only changes with the
+    /// next refresh, so follow up with e.g. a `full_refresh(..)` to flip to the page.
+    pub fn pan_to_page(&self, page: u32) -> io::Result<()> {
+        if page >= self.pages {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The page lies outside of the mapped framebuffer",
+            ));
         }
+        let mut var_screen_info = self.var_screen_info.clone();
+        var_screen_info.yoffset = page * self.var_screen_info.yres;
+        self.checked_ioctl(FBIOPAN_DISPLAY, &mut var_screen_info)?;
+        self.displayed_page.store(page, Ordering::Relaxed);
+        Ok(())
     }
 
-    fn set_epdc_access(&mut self, state: bool) {
+    /// Start of the memory of the draw page
+    pub(crate) fn draw_buffer(&self) -> *mut u8 {
+        self.page_buffer(self.draw_page())
+    }
+
+    /// Length in bytes of one screen sized page
+    pub(crate) fn page_length(&self) -> usize {
+        self.fix_screen_info.line_length as usize * self.var_screen_info.yres as usize
+    }
+
+    /// Start of the memory of `page`, which needs to be below `page_count()`
+    pub(crate) fn page_buffer(&self, page: u32) -> *mut u8 {
+        assert!(
+            page < self.pages,
+            "The page lies outside of the mapped framebuffer"
+        );
         unsafe {
-            libc::ioctl(
-                self.device.as_raw_fd(),
-                if state {
-                    MXCFB_ENABLE_EPDC_ACCESS
-                } else {
-                    MXCFB_DISABLE_EPDC_ACCESS
-                },
-            );
-        };
+            self.frame
+                .data()
+                .offset((page as usize * self.page_length()) as isize)
+        }
+    }
+
+    /// Leaves the driver the way it was found, so that `xochitl` works as usual afterwards.
+    /// Puts back the var screeninfo found when the framebuffer was created, the default
+    /// `DriverState`, and the temperature, power-down delay and colormap found before they
+    /// were first changed through the framebuffer. The waveform modes can't be read back
+    /// and are left as they are. Returns the first error, after trying all of them.
+    ///
+    /// Dropping the framebuffer does the same, but a process that exits without dropping
+    /// it, e.g. through `std::process::exit(..)`, needs to call this before starting
+    /// `xochitl`. Nothing should be drawn afterwards.
+    pub fn restore(&self) -> io::Result<()> {
+        let pinned =
+            ::std::mem::replace(&mut *self.pinned.lock().unwrap(), PinnedSettings::default());
+        let mut results = vec![state::restore(self, &DriverState::default())];
+        if pinned.temperature {
+            results.push(self.set_temperature(display_temp::TEMP_USE_AMBIENT as i32));
+        }
+        if let Some(delay) = pinned.powerdown_delay {
+            results.push(self.set_powerdown_delay(delay));
+        }
+        if let Some(ref colormap) = pinned.colormap {
+            results.push(self.set_colormap(colormap));
+        }
+        // Putting them back recorded the current settings as the ones found
+        *self.pinned.lock().unwrap() = PinnedSettings::default();
+        let mut var_screen_info = self.original_var_screen_info.clone();
+        if !Framebuffer::put_var_screeninfo(&*self.device, &mut var_screen_info) {
+            results.push(Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Failed to restore the var screeninfo",
+            )));
+        }
+        results.into_iter().collect()
+    }
+
+    /// The driver settings as last set through the framebuffer
+    pub fn driver_state(&self) -> DriverState {
+        *self.driver_state.lock().unwrap()
+    }
+
+    /// Sets the EPDC access until the returned guard is dropped
+    pub fn scoped_epdc_access<'f>(&'f self, state: bool) -> io::Result<DriverStateGuard<'f, 'a>> {
+        let guard = DriverStateGuard::new(self);
+        self.set_epdc_access(state)?;
+        Ok(guard)
+    }
+
+    /// Sets the autoupdate mode until the returned guard is dropped
+    pub fn scoped_autoupdate_mode<'f>(
+        &'f self,
+        mode: auto_update_mode,
+    ) -> io::Result<DriverStateGuard<'f, 'a>> {
+        let guard = DriverStateGuard::new(self);
+        self.set_autoupdate_mode(mode)?;
+        Ok(guard)
     }
 
-    fn set_autoupdate_mode(&mut self, mode: u32) {
-        let m = mode.to_owned();
+    /// Sets the update scheme until the returned guard is dropped
+    pub fn scoped_update_scheme<'f>(
+        &'f self,
+        scheme: update_scheme,
+    ) -> io::Result<DriverStateGuard<'f, 'a>> {
+        let guard = DriverStateGuard::new(self);
+        self.set_update_scheme(scheme)?;
+        Ok(guard)
+    }
+
+    /// Issues `request` with a pointer to `arg`, returning the error reported by the driver.
+    /// `arg` needs to be the plain `repr(C)` struct the request takes, and is rejected if
+    /// its size doesn't match.
+    pub(crate) fn checked_ioctl<T>(&self, request: NativeWidthType, arg: &mut T) -> io::Result<()> {
         unsafe {
-            libc::ioctl(
-                self.device.as_raw_fd(),
-                MXCFB_SET_AUTO_UPDATE_MODE,
-                &m as *const u32,
-            );
+            let arg = as_bytes(arg);
+            check_arg_size(request, arg)?;
+            self.device.ioctl(request, arg)
+        }
+    }
+}
+
+impl<'a> framebuffer::FramebufferBase<'a> for Framebuffer<'a> {
+    fn new(path_to_device: &str) -> Framebuffer {
+        Framebuffer::from_device(Box::new(LinuxDevice::open(path_to_device).unwrap()))
+    }
+
+    fn set_epdc_access(&self, state: bool) -> io::Result<()> {
+        let request = if state {
+            MXCFB_ENABLE_EPDC_ACCESS
+        } else {
+            MXCFB_DISABLE_EPDC_ACCESS
         };
+        unsafe { self.device.ioctl(request, &mut []) }?;
+        self.driver_state.lock().unwrap().epdc_access = state;
+        Ok(())
     }
 
-    fn set_update_scheme(&mut self, scheme: u32) {
-        let s = scheme.to_owned();
-        unsafe {
-            libc::ioctl(
-                self.device.as_raw_fd(),
-                MXCFB_SET_UPDATE_SCHEME,
-                &s as *const u32,
-            );
+    fn set_autoupdate_mode(&self, mode: auto_update_mode) -> io::Result<()> {
+        let mut m = mode as u32;
+        self.checked_ioctl(MXCFB_SET_AUTO_UPDATE_MODE, &mut m)?;
+        self.driver_state.lock().unwrap().autoupdate_mode = mode;
+        Ok(())
+    }
+
+    fn set_update_scheme(&self, scheme: update_scheme) -> io::Result<()> {
+        let mut s = scheme as u32;
+        self.checked_ioctl(MXCFB_SET_UPDATE_SCHEME, &mut s)?;
+        self.driver_state.lock().unwrap().update_scheme = scheme;
+        Ok(())
+    }
+
+    fn set_waveform_modes(&self, modes: &mxcfb_waveform_modes) -> io::Result<()> {
+        let mut m = *modes;
+        self.checked_ioctl(MXCFB_SET_WAVEFORM_MODES, &mut m)
+    }
+
+    fn set_temperature(&self, celsius: i32) -> io::Result<()> {
+        let mut t = celsius;
+        self.checked_ioctl(MXCFB_SET_TEMPERATURE, &mut t)?;
+        self.pinned.lock().unwrap().temperature = celsius != display_temp::TEMP_USE_AMBIENT as i32;
+        Ok(())
+    }
+
+    fn set_powerdown_delay(&self, delay: Option<Duration>) -> io::Result<()> {
+        let mut ms = match delay {
+            Some(delay) => {
+                let ms = delay.as_secs() * 1000 + u64::from(delay.subsec_millis());
+                ::std::cmp::min(ms, i32::max_value() as u64) as i32
+            }
+            None => FB_POWERDOWN_DISABLE,
         };
+        {
+            let mut pinned = self.pinned.lock().unwrap();
+            if pinned.powerdown_delay.is_none() {
+                pinned.powerdown_delay = self.get_powerdown_delay().ok();
+            }
+        }
+        self.checked_ioctl(MXCFB_SET_PWRDOWN_DELAY, &mut ms)
+    }
+
+    fn get_colormap(&self) -> io::Result<Colormap> {
+        let mut colormap = Colormap::new(0, GRAY_LUT_SIZE);
+        {
+            let mut cmap = colormap
+                .as_fb_cmap()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            self.checked_ioctl(FBIOGETCMAP, &mut cmap)?;
+        }
+        Ok(colormap)
+    }
+
+    fn set_colormap(&self, colormap: &Colormap) -> io::Result<()> {
+        if self.var_screen_info.bits_per_pixel != 8 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The PxP only applies the colormap in the 8-bit grayscale mode",
+            ));
+        }
+        if colormap.start as usize + colormap.len() > GRAY_LUT_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The colormap reaches past the 256 entries of the framebuffer",
+            ));
+        }
+        {
+            let mut pinned = self.pinned.lock().unwrap();
+            if pinned.colormap.is_none() {
+                pinned.colormap = self.get_colormap().ok();
+            }
+        }
+        // The driver only reads the entries, but the struct wants mutable pointers
+        let mut colormap = colormap.clone();
+        let mut cmap = colormap
+            .as_fb_cmap()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.checked_ioctl(FBIOPUTCMAP, &mut cmap)
     }
 
-    fn get_fix_screeninfo(device: &File) -> FixScreeninfo {
+    fn get_powerdown_delay(&self) -> io::Result<Option<Duration>> {
+        let mut ms: i32 = 0;
+        self.checked_ioctl(MXCFB_GET_PWRDOWN_DELAY, &mut ms)?;
+        if ms < 0 {
+            return Ok(None);
+        }
+        Ok(Some(Duration::from_millis(ms as u64)))
+    }
+
+    fn get_work_buffer(&self) -> io::Result<u64> {
+        let mut addr: c_ulong = 0;
+        self.checked_ioctl(MXCFB_GET_WORK_BUFFER, &mut addr)?;
+        Ok(addr as u64)
+    }
+
+    fn get_fix_screeninfo(device: &dyn FramebufferDevice) -> FixScreeninfo {
         let mut info: FixScreeninfo = Default::default();
-        let result = unsafe { ioctl(device.as_raw_fd(), FBIOGET_FSCREENINFO, &mut info) };
-        if result != 0 {
+        let result = unsafe { device.ioctl(FBIOGET_FSCREENINFO, as_bytes(&mut info)) };
+        if result.is_err() {
             panic!("FBIOGET_FSCREENINFO failed");
         }
         info
     }
 
-    fn get_var_screeninfo(device: &File) -> VarScreeninfo {
+    fn get_var_screeninfo(device: &dyn FramebufferDevice) -> VarScreeninfo {
         let mut info: VarScreeninfo = Default::default();
-        let result = unsafe { ioctl(device.as_raw_fd(), FBIOGET_VSCREENINFO, &mut info) };
-        if result != 0 {
+        let result = unsafe { device.ioctl(FBIOGET_VSCREENINFO, as_bytes(&mut info)) };
+        if result.is_err() {
             panic!("FBIOGET_VSCREENINFO failed");
         }
         info
     }
 
-    fn put_var_screeninfo(device: &File, var_screen_info: &mut VarScreeninfo) -> bool {
-        let result = unsafe { ioctl(device.as_raw_fd(), FBIOPUT_VSCREENINFO, var_screen_info) };
-        result == 0
+    fn put_var_screeninfo(
+        device: &dyn FramebufferDevice,
+        var_screen_info: &mut VarScreeninfo,
+    ) -> bool {
+        unsafe { device.ioctl(FBIOPUT_VSCREENINFO, as_bytes(var_screen_info)) }.is_ok()
+    }
+}
+
+impl<'a> Drop for Framebuffer<'a> {
+    fn drop(&mut self) {
+        if let Err(e) = self.restore() {
+            warn!("Failed to restore the driver: {}", e);
+        }
     }
 }
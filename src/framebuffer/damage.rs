@@ -0,0 +1,192 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+use framebuffer::cgmath;
+use framebuffer::common::mxcfb_rect;
+
+/// Rects closer than this many pixels to each other are merged by default
+pub const DEFAULT_MERGE_DISTANCE: u32 = 16;
+
+/// The EPDC (PxP) processes the framebuffer in blocks of 8x8 pixels
+pub(crate) const EPDC_BLOCK_SIZE: u32 = 8;
+
+/// Turns a bitmap of dirty blocks into rects. Horizontal runs of dirty blocks within each
+/// block row become spans, and spans covering the same columns in consecutive block rows
+/// are combined into a single rect. `origin` is the position of the first block, in blocks of
+/// `block_size` pixels. The rects are clipped to `bounds`.
+pub(crate) fn dirty_blocks_to_rects(
+    dirty: &[bool],
+    cols: u32,
+    block_size: u32,
+    origin: cgmath::Point2<u32>,
+    bounds: cgmath::Vector2<u32>,
+) -> Vec<mxcfb_rect> {
+    // (first col, last col, first row) of the rects still growing downwards
+    let mut open: Vec<(u32, u32, u32)> = Vec::new();
+    let mut rects = Vec::new();
+    let to_rect = |first_col: u32, last_col: u32, first_row: u32, last_row: u32| {
+        let left = (origin.x + first_col) * block_size;
+        let top = (origin.y + first_row) * block_size;
+        let right = ::std::cmp::min((origin.x + last_col + 1) * block_size, bounds.x);
+        let bottom = ::std::cmp::min((origin.y + last_row + 1) * block_size, bounds.y);
+        mxcfb_rect {
+            top,
+            left,
+            width: right - left,
+            height: bottom - top,
+        }
+    };
+
+    let rows = dirty.len() as u32 / cols;
+    for row in 0..rows + 1 {
+        let mut spans = Vec::new();
+        if row < rows {
+            let line = &dirty[(row * cols) as usize..((row + 1) * cols) as usize];
+            let mut col = 0;
+            while col < cols {
+                if line[col as usize] {
+                    let first = col;
+                    while col + 1 < cols && line[col as usize + 1] {
+                        col += 1;
+                    }
+                    spans.push((first, col));
+                }
+                col += 1;
+            }
+        }
+
+        let mut still_open = Vec::with_capacity(spans.len());
+        for (first, last) in spans {
+            match open.iter().position(|o| o.0 == first && o.1 == last) {
+                Some(i) => still_open.push(open.swap_remove(i)),
+                None => still_open.push((first, last, row)),
+            }
+        }
+        for (first, last, first_row) in open {
+            rects.push(to_rect(first, last, first_row, row - 1));
+        }
+        open = still_open;
+    }
+    rects
+}
+
+/// Returns true if the gap between `a` and `b` is at most `distance` pixels on both axes
+fn within_distance(a: &mxcfb_rect, b: &mxcfb_rect, distance: u32) -> bool {
+    a.left <= b.left + b.width + distance
+        && b.left <= a.left + a.width + distance
+        && a.top <= b.top + b.height + distance
+        && b.top <= a.top + a.height + distance
+}
+
+/// Accumulates the regions of the framebuffer that have been drawn over but not refreshed yet.
+///
+/// Every `FramebufferDraw` call, as well as `restore_region(..)` and `write_frame(..)`, adds
+/// the area it touched. `write_pixel(..)` doesn't, so code drawing pixel by pixel needs to call
+/// `add(..)` itself. Tracked rects that overlap or lie within `merge_distance` pixels of each
+/// other are merged, which keeps the number of refreshes needed to cover them down.
+///
+/// Refreshing a region through `partial_refresh(..)` or `full_refresh(..)` clears the damage
+/// it covers, and `FramebufferRefresh::flush_damage(..)` refreshes whatever is left.
+pub struct DamageTracker {
+    bounds: cgmath::Vector2<u32>,
+    merge_distance: AtomicU32,
+    rects: Mutex<Vec<mxcfb_rect>>,
+}
+
+impl DamageTracker {
+    pub fn new(bounds: cgmath::Vector2<u32>, merge_distance: u32) -> DamageTracker {
+        DamageTracker {
+            bounds,
+            merge_distance: AtomicU32::new(merge_distance),
+            rects: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn merge_distance(&self) -> u32 {
+        self.merge_distance.load(Ordering::Relaxed)
+    }
+
+    /// Only affects the rects added afterwards
+    pub fn set_merge_distance(&self, merge_distance: u32) {
+        self.merge_distance.store(merge_distance, Ordering::Relaxed);
+    }
+
+    /// Marks `rect` as damaged. Parts of it outside of the screen are ignored.
+    pub fn add(&self, rect: mxcfb_rect) {
+        let mut merged = match self.clip(&rect) {
+            Some(rect) => rect,
+            None => return,
+        };
+        let distance = self.merge_distance();
+        let mut rects = self.rects.lock().unwrap();
+        // Merging may grow the rect into other tracked ones, so repeat until stable
+        loop {
+            let count = rects.len();
+            rects.retain(|r| {
+                if within_distance(r, &merged, distance) {
+                    merged = merged.merge_rect(r);
+                    false
+                } else {
+                    true
+                }
+            });
+            if rects.len() == count {
+                break;
+            }
+        }
+        rects.push(merged);
+    }
+
+    /// Forgets the tracked rects that are entirely contained in `rect`, as it has been refreshed
+    pub fn clear_region(&self, rect: &mxcfb_rect) {
+        let right = rect.left + rect.width;
+        let bottom = rect.top + rect.height;
+        self.rects.lock().unwrap().retain(|r| {
+            r.left < rect.left
+                || r.top < rect.top
+                || r.left + r.width > right
+                || r.top + r.height > bottom
+        });
+    }
+
+    pub fn clear(&self) {
+        self.rects.lock().unwrap().clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rects.lock().unwrap().is_empty()
+    }
+
+    /// Returns a copy of the currently tracked rects
+    pub fn rects(&self) -> Vec<mxcfb_rect> {
+        self.rects.lock().unwrap().clone()
+    }
+
+    /// Returns the currently tracked rects and stops tracking them
+    pub fn take(&self) -> Vec<mxcfb_rect> {
+        ::std::mem::replace(&mut *self.rects.lock().unwrap(), Vec::new())
+    }
+
+    /// Clips `rect` to the screen. Draw calls close to the top or left edge may return rects
+    /// whose origin wrapped around below zero, those are treated as the negative values they are.
+    fn clip(&self, rect: &mxcfb_rect) -> Option<mxcfb_rect> {
+        let clamp = |start: u32, len: u32, max: u32| {
+            let start = i64::from(start as i32);
+            let end = start + i64::from(len);
+            let start = start.max(0).min(i64::from(max));
+            let end = end.max(0).min(i64::from(max));
+            (start as u32, (end - start) as u32)
+        };
+        let (left, width) = clamp(rect.left, rect.width, self.bounds.x);
+        let (top, height) = clamp(rect.top, rect.height, self.bounds.y);
+        if width == 0 || height == 0 {
+            return None;
+        }
+        Some(mxcfb_rect {
+            top,
+            left,
+            width,
+            height,
+        })
+    }
+}
@@ -0,0 +1,573 @@
+use libc;
+use mmap;
+use mmap::MemoryMap;
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use framebuffer::cmap::{fb_cmap, Colormap};
+use framebuffer::common::{
+    NativeWidthType, FBIOGETCMAP, FBIOGET_FSCREENINFO, FBIOGET_VSCREENINFO, FBIOPAN_DISPLAY,
+    FBIOPUTCMAP, FBIOPUT_VSCREENINFO,
+};
+use framebuffer::screeninfo::{FixScreeninfo, VarScreeninfo};
+
+/// The device the framebuffer issues its ioctls to and maps its memory from.
+///
+/// Requests take their argument as the bytes of the struct the driver expects, which
+/// receive whatever the driver writes back. Requests that don't take an argument are
+/// issued with an empty slice.
+pub trait FramebufferDevice: Send + Sync {
+    /// Issues `request`, returning the error reported by the driver.
+    ///
+    /// # Safety
+    ///
+    /// `arg` needs to hold a valid instance of the struct `request` takes, which is
+    /// `arg_size(request)` bytes long, and any pointer in it needs to be valid for the driver
+    /// to read from or write to.
+    unsafe fn ioctl(&self, request: NativeWidthType, arg: &mut [u8]) -> io::Result<()>;
+    /// Maps the first `len` bytes of the framebuffer memory
+    fn map(&self, len: usize) -> io::Result<MemoryMap>;
+}
+
+/// Returns the bytes of `arg`, for passing it to `FramebufferDevice::ioctl(..)`
+///
+/// # Safety
+///
+/// `T` needs to be a `repr(C)` struct of plain integers without padding, such as the ones
+/// in `common` and `screeninfo`, so that any bytes written through the slice leave a valid
+/// `T` behind.
+pub unsafe fn as_bytes<T>(arg: &mut T) -> &mut [u8] {
+    ::std::slice::from_raw_parts_mut(arg as *mut T as *mut u8, ::std::mem::size_of::<T>())
+}
+
+/// The size of the argument `request` takes. The size is encoded in the request number,
+/// except for the legacy fb requests, whose structs are looked up instead. Requests without
+/// an argument take 0 bytes.
+pub fn arg_size(request: NativeWidthType) -> usize {
+    match request {
+        FBIOGET_VSCREENINFO | FBIOPUT_VSCREENINFO | FBIOPAN_DISPLAY => {
+            ::std::mem::size_of::<VarScreeninfo>()
+        }
+        FBIOGET_FSCREENINFO => ::std::mem::size_of::<FixScreeninfo>(),
+        FBIOGETCMAP | FBIOPUTCMAP => ::std::mem::size_of::<fb_cmap>(),
+        _ => ioc_size!(request as u32) as usize,
+    }
+}
+
+/// Fails with `InvalidInput` unless `arg` is as long as the argument `request` takes
+pub(crate) fn check_arg_size(request: NativeWidthType, arg: &[u8]) -> io::Result<()> {
+    let expected = arg_size(request);
+    if arg.len() != expected {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Request {:08x} takes {} bytes, got {}",
+                request,
+                expected,
+                arg.len()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Reads a `T` out of the start of `bytes`, which must be at least as long as `T`
+pub(crate) fn from_bytes<T>(bytes: &[u8]) -> T {
+    assert!(bytes.len() >= ::std::mem::size_of::<T>());
+    unsafe { ::std::ptr::read_unaligned(bytes.as_ptr() as *const T) }
+}
+
+/// Maps anonymous memory, standing in for the framebuffer memory of devices without one
+fn map_anonymous(len: usize) -> io::Result<MemoryMap> {
+    MemoryMap::new(
+        len,
+        &[mmap::MapOption::MapReadable, mmap::MapOption::MapWritable],
+    ).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+}
+
+/// A framebuffer device node such as `/dev/fb0`
+pub struct LinuxDevice {
+    file: File,
+}
+
+impl LinuxDevice {
+    pub fn open(path: &str) -> io::Result<LinuxDevice> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(LinuxDevice { file })
+    }
+}
+
+impl FramebufferDevice for LinuxDevice {
+    unsafe fn ioctl(&self, request: NativeWidthType, arg: &mut [u8]) -> io::Result<()> {
+        // The driver reads and writes as many bytes as the request says, whatever the slice
+        check_arg_size(request, arg)?;
+        let ptr = if arg.is_empty() {
+            ::std::ptr::null_mut()
+        } else {
+            arg.as_mut_ptr()
+        };
+        if libc::ioctl(self.file.as_raw_fd(), request, ptr) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn map(&self, len: usize) -> io::Result<MemoryMap> {
+        MemoryMap::new(
+            len,
+            &[
+                mmap::MapOption::MapReadable,
+                mmap::MapOption::MapWritable,
+                mmap::MapOption::MapFd(self.file.as_raw_fd()),
+                mmap::MapOption::MapOffset(0),
+                mmap::MapOption::MapNonStandardFlags(libc::MAP_SHARED),
+            ],
+        ).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        return "-".to_owned();
+    }
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s == "-" {
+        return Some(Vec::new());
+    }
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// A single ioctl as recorded in a trace
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEntry {
+    /// Time elapsed since the start of the trace
+    pub timestamp: Duration,
+    pub request: NativeWidthType,
+    /// The errno the request failed with, if it did
+    pub errno: Option<i32>,
+    /// The argument as it was passed to the driver
+    pub input: Vec<u8>,
+    /// The argument after the driver wrote back to it
+    pub output: Vec<u8>,
+}
+
+impl TraceEntry {
+    /// Formats the entry as a line of a trace file:
+    /// `<seconds> <request> <ok|err:errno> <input> <output>`, with the request and the
+    /// arguments in hex and `-` standing for an empty argument.
+    pub fn to_line(&self) -> String {
+        let result = match self.errno {
+            Some(errno) => format!("err:{}", errno),
+            None => "ok".to_owned(),
+        };
+        format!(
+            "{}.{:06} {:08x} {} {} {}",
+            self.timestamp.as_secs(),
+            self.timestamp.subsec_micros(),
+            self.request,
+            result,
+            to_hex(&self.input),
+            to_hex(&self.output)
+        )
+    }
+
+    /// Parses a line written by `to_line()`
+    pub fn from_line(line: &str) -> Option<TraceEntry> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 5 {
+            return None;
+        }
+        let mut time = fields[0].splitn(2, '.');
+        let secs = time.next()?.parse::<u64>().ok()?;
+        let micros = time.next()?.parse::<u32>().ok()?;
+        let errno = match fields[2] {
+            "ok" => None,
+            result if result.starts_with("err:") => Some(result[4..].parse::<i32>().ok()?),
+            _ => return None,
+        };
+        Some(TraceEntry {
+            timestamp: Duration::new(secs, micros * 1000),
+            request: NativeWidthType::from_str_radix(fields[1], 16).ok()?,
+            errno,
+            input: from_hex(fields[3])?,
+            output: from_hex(fields[4])?,
+        })
+    }
+
+    fn result(&self) -> io::Result<()> {
+        match self.errno {
+            Some(errno) => Err(io::Error::from_raw_os_error(errno)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reads the entries of a trace written by a `TracingDevice`. Empty lines and lines
+/// starting with `#` are skipped.
+pub fn read_trace<R: io::Read>(reader: R) -> io::Result<Vec<TraceEntry>> {
+    let mut entries = Vec::new();
+    for (number, line) in BufReader::new(reader).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match TraceEntry::from_line(line) {
+            Some(entry) => entries.push(entry),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Malformed trace entry on line {}", number + 1),
+                ))
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Issues the requests of `entries` against `device` in order, with the arguments they were
+/// recorded with. If `realtime` is set, the original timing between the requests is kept.
+/// Returns the entries as `device` answered them, to be compared with the recorded ones.
+///
+/// The colormap requests are issued with channels of their own, holding the recorded ones.
+/// Requests recorded with an argument of the wrong size fail with `EINVAL` instead of being
+/// issued.
+pub fn replay_trace(
+    entries: &[TraceEntry],
+    device: &dyn FramebufferDevice,
+    realtime: bool,
+) -> Vec<TraceEntry> {
+    let start = Instant::now();
+    let mut replayed = Vec::with_capacity(entries.len());
+    for entry in entries {
+        if realtime {
+            let elapsed = start.elapsed();
+            if entry.timestamp > elapsed {
+                ::std::thread::sleep(entry.timestamp - elapsed);
+            }
+        }
+        let mut arg = entry.input.clone();
+        let result = match entry.request {
+            FBIOGETCMAP | FBIOPUTCMAP => replay_colormap(entry.request, &mut arg, device),
+            _ if arg.len() != arg_size(entry.request) => {
+                Err(io::Error::from_raw_os_error(libc::EINVAL))
+            }
+            // The argument is the right size for the request and carries no pointers
+            _ => unsafe { device.ioctl(entry.request, &mut arg) },
+        };
+        replayed.push(TraceEntry {
+            timestamp: start.elapsed(),
+            request: entry.request,
+            errno: result.err().map(|e| e.raw_os_error().unwrap_or(libc::EIO)),
+            input: entry.input.clone(),
+            output: arg,
+        });
+    }
+    replayed
+}
+
+/// Issues a colormap request whose argument was recorded by `trace_arg(..)`, replacing
+/// `arg` with the colormap as the driver left it
+fn replay_colormap(
+    request: NativeWidthType,
+    arg: &mut Vec<u8>,
+    device: &dyn FramebufferDevice,
+) -> io::Result<()> {
+    let mut colormap = match Colormap::from_bytes(arg) {
+        Some(colormap) => colormap,
+        None => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+    };
+    let result = match colormap.as_fb_cmap() {
+        // The channels of the colormap outlive the request
+        Ok(mut cmap) => unsafe { device.ioctl(request, as_bytes(&mut cmap)) },
+        Err(_) => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+    };
+    *arg = colormap.to_bytes();
+    result
+}
+
+/// The argument of `request` as it gets recorded in a trace. The colormap requests point
+/// at the channels, so the channels are recorded in place of the pointers, see
+/// `Colormap::to_bytes()`.
+///
+/// # Safety
+///
+/// Same as for `FramebufferDevice::ioctl(..)`.
+unsafe fn trace_arg(request: NativeWidthType, arg: &[u8]) -> Vec<u8> {
+    match request {
+        FBIOGETCMAP | FBIOPUTCMAP if arg.len() == arg_size(request) => {
+            Colormap::from_fb_cmap(&from_bytes::<fb_cmap>(arg)).to_bytes()
+        }
+        _ => arg.to_vec(),
+    }
+}
+
+/// Wraps another device and records every ioctl issued to it, along with its timing,
+/// its arguments and its result, as a line of a trace written to `writer`.
+pub struct TracingDevice {
+    inner: Box<dyn FramebufferDevice>,
+    start: Instant,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl TracingDevice {
+    pub fn new(
+        inner: Box<dyn FramebufferDevice>,
+        mut writer: Box<dyn Write + Send>,
+    ) -> TracingDevice {
+        if let Err(e) = writeln!(writer, "# libremarkable ioctl trace") {
+            warn!("Failed to write the ioctl trace header: {}", e);
+        }
+        TracingDevice {
+            inner,
+            start: Instant::now(),
+            writer: Mutex::new(writer),
+        }
+    }
+
+    /// Records the trace into the file at `path`, which gets truncated
+    pub fn create(inner: Box<dyn FramebufferDevice>, path: &str) -> io::Result<TracingDevice> {
+        let file = File::create(path)?;
+        Ok(TracingDevice::new(inner, Box::new(file)))
+    }
+}
+
+impl FramebufferDevice for TracingDevice {
+    unsafe fn ioctl(&self, request: NativeWidthType, arg: &mut [u8]) -> io::Result<()> {
+        let input = trace_arg(request, arg);
+        let timestamp = self.start.elapsed();
+        let result = self.inner.ioctl(request, arg);
+        let entry = TraceEntry {
+            timestamp,
+            request,
+            errno: match result {
+                Ok(()) => None,
+                Err(ref e) => Some(e.raw_os_error().unwrap_or(libc::EIO)),
+            },
+            input,
+            output: trace_arg(request, arg),
+        };
+        // Flushed right away so that the trace survives the crash being investigated
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(e) = writeln!(writer, "{}", entry.to_line()).and_then(|_| writer.flush()) {
+            warn!("Failed to write to the ioctl trace: {}", e);
+        }
+        result
+    }
+
+    fn map(&self, len: usize) -> io::Result<MemoryMap> {
+        self.inner.map(len)
+    }
+}
+
+/// Answers the ioctls issued to it with the results and output arguments recorded in a
+/// trace, in order. A request that doesn't match the next recorded one fails with
+/// `InvalidData`, as the trace no longer describes what is happening.
+pub struct ReplayDevice {
+    entries: Mutex<VecDeque<TraceEntry>>,
+}
+
+impl ReplayDevice {
+    pub fn new(entries: Vec<TraceEntry>) -> ReplayDevice {
+        ReplayDevice {
+            entries: Mutex::new(entries.into_iter().collect()),
+        }
+    }
+
+    pub fn open(path: &str) -> io::Result<ReplayDevice> {
+        Ok(ReplayDevice::new(read_trace(File::open(path)?)?))
+    }
+
+    /// Number of recorded requests that haven't been replayed yet
+    pub fn remaining(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+impl FramebufferDevice for ReplayDevice {
+    unsafe fn ioctl(&self, request: NativeWidthType, arg: &mut [u8]) -> io::Result<()> {
+        let entry = match self.entries.lock().unwrap().pop_front() {
+            Some(entry) => entry,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "The trace has no more requests to replay",
+                ))
+            }
+        };
+        let is_colormap = request == FBIOGETCMAP || request == FBIOPUTCMAP;
+        let expected_len = if is_colormap {
+            arg_size(request)
+        } else {
+            entry.output.len()
+        };
+        if entry.request != request || arg.len() != expected_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Request {:08x} doesn't match the one in the trace ({:08x})",
+                    request, entry.request
+                ),
+            ));
+        }
+        if entry.input != trace_arg(request, arg) {
+            debug!("Request {:08x} replayed with a different argument", request);
+        }
+        if is_colormap {
+            // The trace holds the channels, which get copied into the ones `arg` points at
+            let colormap = Colormap::from_bytes(&entry.output).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Malformed colormap in the trace",
+                )
+            })?;
+            colormap
+                .copy_to_fb_cmap(&from_bytes::<fb_cmap>(arg))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        } else {
+            arg.copy_from_slice(&entry.output);
+        }
+        entry.result()
+    }
+
+    fn map(&self, len: usize) -> io::Result<MemoryMap> {
+        map_anonymous(len)
+    }
+}
+
+/// An in-memory device for running without a display. It reports the screeninfo of the
+/// reMarkable panel, applies the rotation and virtual resolution requested through
+/// `FBIOPUT_VSCREENINFO` the way the driver does, rejecting virtual resolutions taller than
+/// the two screens its memory holds, and accepts every other request. The requests it receives are kept
+/// and can be inspected through `requests()`.
+pub struct MockDevice {
+    screeninfo: Mutex<(VarScreeninfo, FixScreeninfo)>,
+    requests: Mutex<Vec<(NativeWidthType, Vec<u8>)>>,
+}
+
+/// Native resolution of the panel, before any rotation
+const MOCK_PANEL_WIDTH: u32 = 1872;
+const MOCK_PANEL_HEIGHT: u32 = 1404;
+/// The driver pads the lines of the framebuffer to a multiple of 32 pixels
+const MOCK_LINE_ALIGNMENT: u32 = 32;
+const MOCK_BITS_PER_PIXEL: u32 = 16;
+/// Number of screens the framebuffer memory holds in either orientation
+const MOCK_MEMORY_PAGES: u32 = 2;
+
+impl MockDevice {
+    pub fn new() -> MockDevice {
+        let mut var: VarScreeninfo = Default::default();
+        var.bits_per_pixel = MOCK_BITS_PER_PIXEL;
+        var.rotate = 1;
+        let mut fix: FixScreeninfo = Default::default();
+        fix.id[..6].copy_from_slice(b"mxc_fb");
+        MockDevice::apply_rotation(&mut var, &mut fix)
+            .expect("A single screen always fits into the memory");
+        MockDevice {
+            screeninfo: Mutex::new((var, fix)),
+            requests: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the requests issued so far along with their arguments, in order
+    pub fn requests(&self) -> Vec<(NativeWidthType, Vec<u8>)> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Lines padded to `MOCK_LINE_ALIGNMENT` pixels, in bytes
+    fn line_length(xres: u32) -> u32 {
+        let padded = (xres + MOCK_LINE_ALIGNMENT - 1) / MOCK_LINE_ALIGNMENT * MOCK_LINE_ALIGNMENT;
+        padded * MOCK_BITS_PER_PIXEL / 8
+    }
+
+    /// Size of the framebuffer memory, enough for `MOCK_MEMORY_PAGES` screens either way round
+    fn memory_length() -> u32 {
+        ::std::cmp::max(
+            MockDevice::line_length(MOCK_PANEL_WIDTH) * MOCK_PANEL_HEIGHT,
+            MockDevice::line_length(MOCK_PANEL_HEIGHT) * MOCK_PANEL_WIDTH,
+        ) * MOCK_MEMORY_PAGES
+    }
+
+    /// Sets the resolution and line length for the rotation of `var`, and keeps the
+    /// requested virtual height unless it is smaller than the screen. Fails with `EINVAL`
+    /// if the virtual resolution doesn't fit into the memory.
+    fn apply_rotation(var: &mut VarScreeninfo, fix: &mut FixScreeninfo) -> io::Result<()> {
+        let (xres, yres) = if var.rotate % 2 == 1 {
+            (MOCK_PANEL_HEIGHT, MOCK_PANEL_WIDTH)
+        } else {
+            (MOCK_PANEL_WIDTH, MOCK_PANEL_HEIGHT)
+        };
+        let line_length = MockDevice::line_length(xres);
+        let smem_len = MockDevice::memory_length();
+        let yres_virtual = ::std::cmp::max(var.yres_virtual, yres);
+        if u64::from(yres_virtual) * u64::from(line_length) > u64::from(smem_len) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        var.xres = xres;
+        var.yres = yres;
+        var.xres_virtual = line_length * 8 / MOCK_BITS_PER_PIXEL;
+        var.yres_virtual = yres_virtual;
+        var.bits_per_pixel = MOCK_BITS_PER_PIXEL;
+        fix.line_length = line_length;
+        fix.smem_len = smem_len;
+        Ok(())
+    }
+}
+
+impl Default for MockDevice {
+    fn default() -> MockDevice {
+        MockDevice::new()
+    }
+}
+
+impl FramebufferDevice for MockDevice {
+    unsafe fn ioctl(&self, request: NativeWidthType, arg: &mut [u8]) -> io::Result<()> {
+        self.requests.lock().unwrap().push((request, arg.to_vec()));
+        let mut screeninfo = self.screeninfo.lock().unwrap();
+        let invalid = || io::Error::from_raw_os_error(libc::EINVAL);
+        match request {
+            FBIOGET_FSCREENINFO => {
+                if arg.len() != ::std::mem::size_of::<FixScreeninfo>() {
+                    return Err(invalid());
+                }
+                arg.copy_from_slice(as_bytes(&mut screeninfo.1));
+            }
+            FBIOGET_VSCREENINFO => {
+                if arg.len() != ::std::mem::size_of::<VarScreeninfo>() {
+                    return Err(invalid());
+                }
+                arg.copy_from_slice(as_bytes(&mut screeninfo.0));
+            }
+            FBIOPUT_VSCREENINFO => {
+                if arg.len() != ::std::mem::size_of::<VarScreeninfo>() {
+                    return Err(invalid());
+                }
+                let mut var: VarScreeninfo = from_bytes(arg);
+                MockDevice::apply_rotation(&mut var, &mut screeninfo.1)?;
+                arg.copy_from_slice(as_bytes(&mut var));
+                screeninfo.0 = var;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn map(&self, len: usize) -> io::Result<MemoryMap> {
+        map_anonymous(len)
+    }
+}
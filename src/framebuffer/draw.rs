@@ -9,6 +9,7 @@ use framebuffer;
 use framebuffer::cgmath::*;
 use framebuffer::common::*;
 use framebuffer::core;
+use framebuffer::fill;
 use framebuffer::FramebufferIO;
 
 macro_rules! min {
@@ -58,12 +59,14 @@ impl<'a> framebuffer::FramebufferDraw for core::Framebuffer<'a> {
                 color::RGB(pixel.data[0], pixel.data[1], pixel.data[2]),
             );
         }
-        mxcfb_rect {
+        let rect = mxcfb_rect {
             top: pos.y as u32,
             left: pos.x as u32,
             width: img.width(),
             height: img.height(),
-        }
+        };
+        self.damage.add(rect);
+        rect
     }
 
     fn draw_line(
@@ -135,12 +138,14 @@ impl<'a> framebuffer::FramebufferDraw for core::Framebuffer<'a> {
         }
 
         let margin = ((width + 1) / 2) as i32;
-        mxcfb_rect {
+        let rect = mxcfb_rect {
             top: (min_y - margin) as u32,
             left: (min_x - margin) as u32,
             width: (max_x - min_x + margin * 2) as u32,
             height: (max_y - min_y + margin * 2) as u32,
-        }
+        };
+        self.damage.add(rect);
+        rect
     }
 
     fn draw_circle(&mut self, pos: Point2<i32>, rad: u32, v: color) -> mxcfb_rect {
@@ -153,12 +158,14 @@ impl<'a> framebuffer::FramebufferDraw for core::Framebuffer<'a> {
                 v,
             );
         }
-        mxcfb_rect {
+        let rect = mxcfb_rect {
             top: pos.y as u32 - rad as u32,
             left: pos.x as u32 - rad as u32,
             width: 2 * rad as u32,
             height: 2 * rad as u32,
-        }
+        };
+        self.damage.add(rect);
+        rect
     }
 
     fn fill_circle(&mut self, pos: Point2<i32>, rad: u32, v: color) -> mxcfb_rect {
@@ -175,12 +182,14 @@ impl<'a> framebuffer::FramebufferDraw for core::Framebuffer<'a> {
                 );
             }
         }
-        mxcfb_rect {
+        let rect = mxcfb_rect {
             top: pos.y as u32 - rad as u32,
             left: pos.x as u32 - rad as u32,
             width: 2 * rad as u32,
             height: 2 * rad as u32,
-        }
+        };
+        self.damage.add(rect);
+        rect
     }
 
     fn draw_bezier(
@@ -218,7 +227,9 @@ impl<'a> framebuffer::FramebufferDraw for core::Framebuffer<'a> {
             };
         }
         let margin = ((width + 1.0) / 2.0) as u32;
-        bbox.expand(margin)
+        let rect = bbox.expand(margin);
+        self.damage.add(rect);
+        rect
     }
 
     fn draw_text(
@@ -287,12 +298,16 @@ impl<'a> framebuffer::FramebufferDraw for core::Framebuffer<'a> {
             }
         }
         // return the height and width of the drawn text so that refresh can be called on it
-        mxcfb_rect {
+        let rect = mxcfb_rect {
             top: min_y as u32,
             left: min_x as u32,
             height: (max_y - min_y) as u32,
             width: (max_x - min_x) as u32,
+        };
+        if !dryrun {
+            self.damage.add(rect);
         }
+        rect
     }
 
     fn draw_rect(&mut self, pos: Point2<i32>, size: Vector2<u32>, border_px: u32, c: color) {
@@ -326,6 +341,18 @@ impl<'a> framebuffer::FramebufferDraw for core::Framebuffer<'a> {
                 );
             }
         }
+        self.damage.add(mxcfb_rect {
+            top: pos.y as u32,
+            left: pos.x as u32,
+            width: size.x,
+            height: size.y,
+        });
+    }
+
+    fn flood_fill(&mut self, seed: Point2<i32>, c: color, tolerance: u8) -> mxcfb_rect {
+        let rect = fill::flood_fill(self, seed, c, tolerance, None);
+        self.damage.add(rect);
+        rect
     }
 
     fn clear(&mut self) {
@@ -333,10 +360,16 @@ impl<'a> framebuffer::FramebufferDraw for core::Framebuffer<'a> {
         let line_length = self.fix_screen_info.line_length as usize;
         unsafe {
             libc::memset(
-                self.frame.data() as *mut libc::c_void,
+                self.draw_buffer() as *mut libc::c_void,
                 std::i32::MAX,
                 line_length * h,
             );
         }
+        self.damage.add(mxcfb_rect {
+            top: 0,
+            left: 0,
+            width: self.var_screen_info.xres,
+            height: self.var_screen_info.yres,
+        });
     }
 }
@@ -0,0 +1,250 @@
+use framebuffer::cgmath;
+use framebuffer::common::{color, mxcfb_rect};
+use framebuffer::core;
+use framebuffer::FramebufferIO;
+
+/// A surface of rgb565 pixels that `flood_fill` can operate on. Implemented for the
+/// `Framebuffer` itself and for `RegionBuffer`, which wraps an off-screen buffer such as
+/// the output of `FramebufferIO::dump_region(..)`.
+pub trait FillTarget {
+    /// Returns the `(width, height)` of the surface
+    fn dimensions(&self) -> cgmath::Vector2<u32>;
+    /// Reads the native components of the pixel at `(x, y)`
+    fn get(&self, x: u32, y: u32) -> [u8; 2];
+    /// Writes the native components of the pixel at `(x, y)`
+    fn set(&mut self, x: u32, y: u32, v: [u8; 2]);
+}
+
+impl<'a> FillTarget for core::Framebuffer<'a> {
+    fn dimensions(&self) -> cgmath::Vector2<u32> {
+        cgmath::Vector2 {
+            x: self.var_screen_info.xres,
+            y: self.var_screen_info.yres,
+        }
+    }
+
+    fn get(&self, x: u32, y: u32) -> [u8; 2] {
+        self.read_pixel(cgmath::Point2 {
+            x: x as usize,
+            y: y as usize,
+        }).as_native()
+    }
+
+    fn set(&mut self, x: u32, y: u32, v: [u8; 2]) {
+        self.write_pixel(
+            cgmath::Point2 {
+                x: x as isize,
+                y: y as isize,
+            },
+            color::from_native(v),
+        );
+    }
+}
+
+/// An off-screen rgb565 buffer of `width` x `height` pixels, laid out
+/// the same way `FramebufferIO::dump_region(..)` returns it.
+pub struct RegionBuffer<'b> {
+    data: &'b mut [u8],
+    width: u32,
+    height: u32,
+}
+
+impl<'b> RegionBuffer<'b> {
+    /// Returns `None` if the length of `data` doesn't match the given dimensions
+    pub fn new(data: &'b mut [u8], width: u32, height: u32) -> Option<RegionBuffer<'b>> {
+        if data.len() != width as usize * height as usize * 2 {
+            return None;
+        }
+        Some(RegionBuffer {
+            data,
+            width,
+            height,
+        })
+    }
+}
+
+impl<'b> FillTarget for RegionBuffer<'b> {
+    fn dimensions(&self) -> cgmath::Vector2<u32> {
+        cgmath::Vector2 {
+            x: self.width,
+            y: self.height,
+        }
+    }
+
+    fn get(&self, x: u32, y: u32) -> [u8; 2] {
+        let idx = (y as usize * self.width as usize + x as usize) * 2;
+        [self.data[idx], self.data[idx + 1]]
+    }
+
+    fn set(&mut self, x: u32, y: u32, v: [u8; 2]) {
+        let idx = (y as usize * self.width as usize + x as usize) * 2;
+        self.data[idx] = v[0];
+        self.data[idx + 1] = v[1];
+    }
+}
+
+/// Keeps track of the pixels that have already been filled so that a fill color
+/// that is itself within `tolerance` of the seed color doesn't loop forever.
+struct VisitedSet {
+    bits: Vec<u64>,
+    origin: cgmath::Point2<u32>,
+    width: u32,
+}
+
+impl VisitedSet {
+    fn new(area: &mxcfb_rect) -> VisitedSet {
+        let len = area.width as usize * area.height as usize;
+        VisitedSet {
+            bits: vec![0; (len + 63) / 64],
+            origin: area.top_left(),
+            width: area.width,
+        }
+    }
+
+    #[inline]
+    fn index(&self, x: u32, y: u32) -> usize {
+        (y - self.origin.y) as usize * self.width as usize + (x - self.origin.x) as usize
+    }
+
+    #[inline]
+    fn contains(&self, x: u32, y: u32) -> bool {
+        let i = self.index(x, y);
+        self.bits[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    #[inline]
+    fn insert(&mut self, x: u32, y: u32) {
+        let i = self.index(x, y);
+        self.bits[i / 64] |= 1 << (i % 64);
+    }
+}
+
+/// Returns true if each of the rgb8 components of `a` and `b` are at most `tolerance` apart
+#[inline]
+fn within_tolerance(a: [u8; 2], b: [u8; 2], tolerance: u8) -> bool {
+    if tolerance == 0 {
+        return a == b;
+    }
+    let a = color::from_native(a).to_rgb8();
+    let b = color::from_native(b).to_rgb8();
+    a.iter()
+        .zip(b.iter())
+        .all(|(&ca, &cb)| (i16::from(ca) - i16::from(cb)).abs() <= i16::from(tolerance))
+}
+
+/// Scanline flood fill. Replaces the pixels connected to `seed` (4-way) whose color is within
+/// `tolerance` of the color at `seed` with `c`. The fill never leaves `clip` if one is given,
+/// nor the bounds of the `target`.
+///
+/// Returns the bounding rect of the filled pixels, ready to be passed to `partial_refresh`,
+/// or `mxcfb_rect::invalid()` if nothing was filled.
+pub fn flood_fill<T: FillTarget>(
+    target: &mut T,
+    seed: cgmath::Point2<i32>,
+    c: color,
+    tolerance: u8,
+    clip: Option<mxcfb_rect>,
+) -> mxcfb_rect {
+    let dims = target.dimensions();
+    let bounds = mxcfb_rect {
+        top: 0,
+        left: 0,
+        width: dims.x,
+        height: dims.y,
+    };
+    let area = match clip {
+        Some(clip) => {
+            let left = ::std::cmp::max(clip.left, bounds.left);
+            let top = ::std::cmp::max(clip.top, bounds.top);
+            let right = ::std::cmp::min(clip.left.saturating_add(clip.width), bounds.width);
+            let bottom = ::std::cmp::min(clip.top.saturating_add(clip.height), bounds.height);
+            if right <= left || bottom <= top {
+                return mxcfb_rect::invalid();
+            }
+            mxcfb_rect {
+                top,
+                left,
+                width: right - left,
+                height: bottom - top,
+            }
+        }
+        None => bounds,
+    };
+    if seed.x < 0 || seed.y < 0 {
+        return mxcfb_rect::invalid();
+    }
+    let (sx, sy) = (seed.x as u32, seed.y as u32);
+    if sx < area.left
+        || sy < area.top
+        || sx >= area.left + area.width
+        || sy >= area.top + area.height
+    {
+        return mxcfb_rect::invalid();
+    }
+
+    let fill = c.as_native();
+    let target_color = target.get(sx, sy);
+    if target_color == fill {
+        return mxcfb_rect::invalid();
+    }
+
+    let (min_x, max_x) = (area.left, area.left + area.width - 1);
+    let (min_y, max_y) = (area.top, area.top + area.height - 1);
+    let mut visited = VisitedSet::new(&area);
+    let mut bbox = mxcfb_rect::invalid();
+
+    let mut stack = vec![(sx, sy)];
+    while let Some((x, y)) = stack.pop() {
+        if visited.contains(x, y) || !within_tolerance(target.get(x, y), target_color, tolerance) {
+            continue;
+        }
+
+        // Extend the span as far as possible on both sides of the seed
+        let mut lx = x;
+        while lx > min_x
+            && !visited.contains(lx - 1, y)
+            && within_tolerance(target.get(lx - 1, y), target_color, tolerance)
+        {
+            lx -= 1;
+        }
+        let mut rx = x;
+        while rx < max_x
+            && !visited.contains(rx + 1, y)
+            && within_tolerance(target.get(rx + 1, y), target_color, tolerance)
+        {
+            rx += 1;
+        }
+
+        for px in lx..rx + 1 {
+            target.set(px, y, fill);
+            visited.insert(px, y);
+        }
+        bbox = bbox.merge_rect(&mxcfb_rect {
+            top: y,
+            left: lx,
+            width: rx - lx + 1,
+            height: 1,
+        });
+
+        // Queue a seed for every run of fillable pixels directly above and below the span
+        let mut neighbours = Vec::with_capacity(2);
+        if y > min_y {
+            neighbours.push(y - 1);
+        }
+        if y < max_y {
+            neighbours.push(y + 1);
+        }
+        for ny in neighbours {
+            let mut in_run = false;
+            for px in lx..rx + 1 {
+                let fillable = !visited.contains(px, ny)
+                    && within_tolerance(target.get(px, ny), target_color, tolerance);
+                if fillable && !in_run {
+                    stack.push((px, ny));
+                }
+                in_run = fillable;
+            }
+        }
+    }
+    bbox
+}
@@ -1,16 +1,91 @@
 #![allow(dead_code)]
+use std::io::Write;
+
+use image;
+
 use framebuffer;
 use framebuffer::cgmath;
 use framebuffer::common;
+use framebuffer::damage::{dirty_blocks_to_rects, EPDC_BLOCK_SIZE};
+use framebuffer::storage;
+
+/// The encodings supported by `FramebufferIO::screenshot(..)`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ScreenshotFormat {
+    /// 24-bit RGB PNG
+    PNG,
+    /// 8-bit grayscale PNG
+    GrayscalePNG,
+    /// 8-bit grayscale binary PGM (P5)
+    PGM,
+    /// RGB JPEG with `quality` in the range 1-100
+    JPEG { quality: u8 },
+    /// The native rgb565_le pixels exactly as `dump_region(..)` returns them, without a header
+    Raw,
+}
+
+/// ITU-R BT.601 luma of a native rgb565 pixel
+#[inline]
+pub(crate) fn native_to_gray8(c1: u8, c2: u8) -> u8 {
+    let rgb = common::color::NATIVE_COMPONENTS(c1, c2).to_rgb8();
+    ((u32::from(rgb[0]) * 299 + u32::from(rgb[1]) * 587 + u32::from(rgb[2]) * 114) / 1000) as u8
+}
+
+impl<'a> framebuffer::core::Framebuffer<'a> {
+    /// Fails unless `rect` lies within the visible screen, which is what every region
+    /// reader and writer operates on
+    pub(crate) fn check_region(&self, rect: &common::mxcfb_rect) -> Result<(), &'static str> {
+        match rect.top.checked_add(rect.height) {
+            Some(bottom) if bottom <= self.var_screen_info.yres => {}
+            _ => return Err("Vertically out of bounds"),
+        }
+        match rect.left.checked_add(rect.width) {
+            Some(right) if right <= self.var_screen_info.xres => {}
+            _ => return Err("Horizontally out of bounds"),
+        }
+        Ok(())
+    }
+
+    /// Converts a row of `rect` straight from the memory of `page` into 8-bit grayscale
+    pub(crate) fn read_gray_row(
+        &self,
+        page: u32,
+        rect: &common::mxcfb_rect,
+        row: u32,
+        out: &mut [u8],
+    ) {
+        let line_length = self.fix_screen_info.line_length as usize;
+        let bytespp = (self.var_screen_info.bits_per_pixel / 8) as usize;
+        let begin = self.page_buffer(page) as *const u8;
+        let row_index = (row + rect.top) as usize * line_length + rect.left as usize * bytespp;
+        for (x, px) in out.iter_mut().enumerate() {
+            let curr_index = (row_index + x * bytespp) as isize;
+            let (c1, c2) = unsafe {
+                (
+                    begin.offset(curr_index).read_volatile(),
+                    begin.offset(curr_index + 1).read_volatile(),
+                )
+            };
+            *px = native_to_gray8(c1, c2);
+        }
+    }
+}
 
 impl<'a> framebuffer::FramebufferIO for framebuffer::core::Framebuffer<'a> {
     fn write_frame(&mut self, frame: &[u8]) {
         unsafe {
-            let begin = self.frame.data() as *mut u8;
+            let begin = self.draw_buffer() as *mut u8;
             for (i, elem) in frame.iter().enumerate() {
                 begin.offset(i as isize).write_volatile(*elem);
             }
         }
+        let line_length = self.fix_screen_info.line_length as usize;
+        self.damage.add(common::mxcfb_rect {
+            top: 0,
+            left: 0,
+            width: self.var_screen_info.xres,
+            height: ((frame.len() + line_length - 1) / line_length) as u32,
+        });
     }
 
     #[inline]
@@ -27,7 +102,7 @@ impl<'a> framebuffer::FramebufferIO for framebuffer::core::Framebuffer<'a> {
         let bytespp = (self.var_screen_info.bits_per_pixel / 8) as isize;
         let curr_index = pos.y * line_length + pos.x * bytespp;
 
-        let begin = self.frame.data() as *mut u8;
+        let begin = self.draw_buffer() as *mut u8;
         let components = col.as_native();
         unsafe {
             begin.offset(curr_index).write_volatile(components[0]);
@@ -46,7 +121,7 @@ impl<'a> framebuffer::FramebufferIO for framebuffer::core::Framebuffer<'a> {
         let bytespp = (self.var_screen_info.bits_per_pixel / 8) as usize;
         let curr_index = pos.y * line_length + pos.x * bytespp;
 
-        let begin = self.frame.data() as *mut u8;
+        let begin = self.draw_buffer() as *mut u8;
         let (c1, c2) = unsafe {
             (
                 begin.offset(curr_index as isize).read_volatile(),
@@ -58,7 +133,7 @@ impl<'a> framebuffer::FramebufferIO for framebuffer::core::Framebuffer<'a> {
 
     fn read_offset(&self, ofst: isize) -> u8 {
         unsafe {
-            let begin = self.frame.data() as *mut u8;
+            let begin = self.draw_buffer() as *mut u8;
             begin.offset(ofst).read_volatile()
         }
     }
@@ -67,16 +142,11 @@ impl<'a> framebuffer::FramebufferIO for framebuffer::core::Framebuffer<'a> {
         if rect.width == 0 || rect.height == 0 {
             return Err("Unable to dump a region with zero height/width");
         }
-        if rect.top + rect.height > self.var_screen_info.height {
-            return Err("Vertically out of bounds");
-        }
-        if rect.left + rect.width > self.var_screen_info.width {
-            return Err("Horizontally out of bounds");
-        }
+        self.check_region(&rect)?;
 
         let line_length = self.fix_screen_info.line_length as u32;
         let bytespp = (self.var_screen_info.bits_per_pixel / 8) as usize;
-        let inbuffer = self.frame.data();
+        let inbuffer = self.draw_buffer();
         let mut outbuffer: Vec<u8> =
             Vec::with_capacity(rect.height as usize * rect.width as usize * bytespp);
         let outbuffer_ptr = outbuffer.as_mut_ptr();
@@ -107,12 +177,7 @@ impl<'a> framebuffer::FramebufferIO for framebuffer::core::Framebuffer<'a> {
         if rect.width == 0 || rect.height == 0 {
             return Err("Unable to restore a region with zero height/width");
         }
-        if rect.top + rect.height > self.var_screen_info.height {
-            return Err("Vertically out of bounds");
-        }
-        if rect.left + rect.width > self.var_screen_info.width {
-            return Err("Horizontally out of bounds");
-        }
+        self.check_region(&rect)?;
 
         let bytespp = (self.var_screen_info.bits_per_pixel / 8) as usize;
         if data.len() as u32 != rect.width * rect.height * bytespp as u32 {
@@ -121,7 +186,7 @@ impl<'a> framebuffer::FramebufferIO for framebuffer::core::Framebuffer<'a> {
 
         let line_length = self.fix_screen_info.line_length as u32;
         let chunk_size = bytespp * rect.width as usize;
-        let outbuffer = self.frame.data();
+        let outbuffer = self.draw_buffer();
         let inbuffer = data.as_ptr();
         let mut written: u32 = 0;
         for y in 0..rect.height {
@@ -133,6 +198,142 @@ impl<'a> framebuffer::FramebufferIO for framebuffer::core::Framebuffer<'a> {
             }
             written += chunk_size as u32;
         }
+        self.damage.add(rect);
         Ok(written)
     }
+
+    fn changed_regions(
+        &self,
+        rect: common::mxcfb_rect,
+        previous: &[u8],
+    ) -> Result<Vec<common::mxcfb_rect>, &'static str> {
+        if rect.width == 0 || rect.height == 0 {
+            return Err("Unable to compare a region with zero height/width");
+        }
+        self.check_region(&rect)?;
+        let bytespp = (self.var_screen_info.bits_per_pixel / 8) as usize;
+        if previous.len() != rect.width as usize * rect.height as usize * bytespp {
+            return Err("Cannot compare region due to mismatched size");
+        }
+
+        // The block grid is anchored to the screen, not to `rect`
+        let first_col = rect.left / EPDC_BLOCK_SIZE;
+        let first_row = rect.top / EPDC_BLOCK_SIZE;
+        let cols = (rect.left + rect.width - 1) / EPDC_BLOCK_SIZE - first_col + 1;
+        let rows = (rect.top + rect.height - 1) / EPDC_BLOCK_SIZE - first_row + 1;
+        let mut dirty = vec![false; (cols * rows) as usize];
+
+        let line_length = self.fix_screen_info.line_length as usize;
+        let chunk_size = bytespp * rect.width as usize;
+        let begin = self.draw_buffer() as *const u8;
+        for y in 0..rect.height {
+            let curr_index = (y + rect.top) as usize * line_length + bytespp * rect.left as usize;
+            let current =
+                unsafe { ::std::slice::from_raw_parts(begin.add(curr_index), chunk_size) };
+            let before = &previous[y as usize * chunk_size..(y as usize + 1) * chunk_size];
+            if current == before {
+                continue;
+            }
+
+            let block_row = (rect.top + y) / EPDC_BLOCK_SIZE - first_row;
+            let mut x = 0;
+            while x < rect.width {
+                // Compare one block worth of pixels at a time
+                let block_col = (rect.left + x) / EPDC_BLOCK_SIZE - first_col;
+                let block_end = ::std::cmp::min(
+                    (rect.left + x) / EPDC_BLOCK_SIZE * EPDC_BLOCK_SIZE + EPDC_BLOCK_SIZE
+                        - rect.left,
+                    rect.width,
+                );
+                let (from, to) = (x as usize * bytespp, block_end as usize * bytespp);
+                if current[from..to] != before[from..to] {
+                    dirty[(block_row * cols + block_col) as usize] = true;
+                }
+                x = block_end;
+            }
+        }
+
+        Ok(dirty_blocks_to_rects(
+            &dirty,
+            cols,
+            EPDC_BLOCK_SIZE,
+            cgmath::Point2 {
+                x: first_col,
+                y: first_row,
+            },
+            cgmath::Vector2 {
+                x: self.var_screen_info.xres,
+                y: self.var_screen_info.yres,
+            },
+        ))
+    }
+
+    fn screenshot(
+        &self,
+        rect: common::mxcfb_rect,
+        format: ScreenshotFormat,
+        writer: &mut dyn Write,
+    ) -> Result<(), &'static str> {
+        if rect.width == 0 || rect.height == 0 {
+            return Err("Unable to capture a region with zero height/width");
+        }
+        self.check_region(&rect)?;
+        if let ScreenshotFormat::JPEG { quality } = format {
+            if quality < 1 || quality > 100 {
+                return Err("JPEG quality must be in the range 1-100");
+            }
+        }
+
+        match format {
+            ScreenshotFormat::Raw => {
+                let rgb565 = self.dump_region(rect)?;
+                writer
+                    .write_all(&rgb565)
+                    .map_err(|_| "Failed to write the raw screenshot")
+            }
+            ScreenshotFormat::PGM => {
+                // Streamed row by row, nothing the size of the region is ever allocated
+                write!(writer, "P5\n{} {}\n255\n", rect.width, rect.height)
+                    .map_err(|_| "Failed to write the PGM header")?;
+                let mut row = vec![0u8; rect.width as usize];
+                for y in 0..rect.height {
+                    self.read_gray_row(self.draw_page(), &rect, y, &mut row);
+                    writer
+                        .write_all(&row)
+                        .map_err(|_| "Failed to write the PGM data")?;
+                }
+                Ok(())
+            }
+            ScreenshotFormat::GrayscalePNG => {
+                let mut gray = vec![0u8; rect.width as usize * rect.height as usize];
+                for (y, row) in gray.chunks_mut(rect.width as usize).enumerate() {
+                    self.read_gray_row(self.draw_page(), &rect, y as u32, row);
+                }
+                image::png::PNGEncoder::new(writer)
+                    .encode(&gray, rect.width, rect.height, image::ColorType::Gray(8))
+                    .map_err(|_| "Failed to encode the PNG")
+            }
+            ScreenshotFormat::PNG | ScreenshotFormat::JPEG { .. } => {
+                let rgb565 = self.dump_region(rect)?;
+                let rgb888 = storage::rgbimage_from_u8_slice(rect.width, rect.height, &rgb565)
+                    .ok_or("Unable to convert the dumped region to RGB")?;
+                match format {
+                    ScreenshotFormat::JPEG { quality } => {
+                        // JPEGEncoder wants a sized writer, which `&mut Write` itself is
+                        let mut writer = writer;
+                        image::jpeg::JPEGEncoder::new_with_quality(&mut writer, quality)
+                            .encode(
+                                &*rgb888,
+                                rect.width,
+                                rect.height,
+                                image::ColorType::RGB(8),
+                            ).map_err(|_| "Failed to encode the JPEG")
+                    }
+                    _ => image::png::PNGEncoder::new(writer)
+                        .encode(&*rgb888, rect.width, rect.height, image::ColorType::RGB(8))
+                        .map_err(|_| "Failed to encode the PNG"),
+                }
+            }
+        }
+    }
 }
@@ -4,6 +4,8 @@ pub mod screeninfo;
 
 pub mod storage;
 
+pub mod undo;
+
 pub mod io;
 
 pub use cgmath;
@@ -28,6 +30,24 @@ pub trait FramebufferIO {
         rect: common::mxcfb_rect,
         data: &[u8],
     ) -> Result<u32, &'static str>;
+    /// Compares `previous`, an earlier `dump_region(..)` of `rect`, with the current contents
+    /// of the framebuffer. Returns the areas that changed as rects aligned to the 8x8 pixel
+    /// blocks of the EPDC, ready to be passed to `partial_refresh`.
+    fn changed_regions(
+        &self,
+        rect: common::mxcfb_rect,
+        previous: &[u8],
+    ) -> Result<Vec<common::mxcfb_rect>, &'static str>;
+    /// Encodes the contents of the specified rectangle in the given `format` and writes
+    /// them into `writer`. Grayscale formats are converted directly from the rgb565
+    /// framebuffer contents without an intermediate RGB copy. A JPEG
+    /// `quality` outside of 1-100 is rejected.
+    fn screenshot(
+        &self,
+        rect: common::mxcfb_rect,
+        format: io::ScreenshotFormat,
+        writer: &mut dyn std::io::Write,
+    ) -> Result<(), &'static str>;
 }
 
 pub mod draw;
@@ -85,48 +105,108 @@ pub trait FramebufferDraw {
     );
     /// Fills rectangle of size `size` at `pos`
     fn fill_rect(&mut self, pos: cgmath::Point2<i32>, size: cgmath::Vector2<u32>, c: common::color);
+    /// Fills the area connected to `seed` whose color is within `tolerance` of the color at
+    /// `seed` with `c`, using a scanline flood fill. Returns the bounding rect of the fill.
+    /// See `fill::flood_fill(..)` to restrict the fill to a clip region or to fill an
+    /// off-screen buffer instead.
+    fn flood_fill(
+        &mut self,
+        seed: cgmath::Point2<i32>,
+        c: common::color,
+        tolerance: u8,
+    ) -> common::mxcfb_rect;
     /// Clears the framebuffer however does not perform a refresh
     fn clear(&mut self);
 }
 
+pub mod fill;
+
+pub mod damage;
+
+pub mod scheduler;
+
+pub mod device;
+
+pub mod temperature;
+
+pub mod waveform;
+
+pub mod completion;
+
+pub mod telemetry;
+
+pub mod collision;
+
+pub mod cmap;
+
+pub mod state;
+
+pub mod simulator;
+
 use std;
 pub mod core;
 pub trait FramebufferBase<'a> {
-    /// Creates a new instance of Framebuffer
+    /// Creates a new instance of Framebuffer on top of the device node at `path_to_device`.
+    /// See `core::Framebuffer::from_device(..)` for other devices.
     fn new(path_to_device: &str) -> core::Framebuffer;
     /// Toggles the EPD Controller (see https://wiki.mobileread.com/wiki/EPD_controller)
-    fn set_epdc_access(&mut self, state: bool);
+    fn set_epdc_access(&self, state: bool) -> std::io::Result<()>;
     /// Toggles autoupdate mode
-    fn set_autoupdate_mode(&mut self, mode: u32);
+    fn set_autoupdate_mode(&self, mode: common::auto_update_mode) -> std::io::Result<()>;
     /// Toggles update scheme
-    fn set_update_scheme(&mut self, scheme: u32);
+    fn set_update_scheme(&self, scheme: common::update_scheme) -> std::io::Result<()>;
+    /// Sets the waveform modes the driver picks from when it selects the waveform of an
+    /// update itself, i.e. for updates sent with `WAVEFORM_MODE_AUTO`
+    fn set_waveform_modes(&self, modes: &mxcfb::mxcfb_waveform_modes) -> std::io::Result<()>;
+    /// Pins the panel temperature, in degrees Celsius, the driver selects waveforms for
+    /// instead of reading the sensor. `display_temp::TEMP_USE_AMBIENT as i32` restores
+    /// the sensor reading.
+    fn set_temperature(&self, celsius: i32) -> std::io::Result<()>;
+    /// Sets how long the EPDC stays powered up after the last update completes.
+    /// `None` keeps it powered up, trading battery life for the latency of the next update.
+    fn set_powerdown_delay(&self, delay: Option<std::time::Duration>) -> std::io::Result<()>;
+    /// Returns the current power-down delay, `None` if powering down is disabled
+    fn get_powerdown_delay(&self) -> std::io::Result<Option<std::time::Duration>>;
+    /// Returns the physical address of the working buffer of the EPDC
+    fn get_work_buffer(&self) -> std::io::Result<u64>;
+    /// Reads the `cmap::GRAY_LUT_SIZE` entries of the colormap of the framebuffer
+    fn get_colormap(&self) -> std::io::Result<cmap::Colormap>;
+    /// Installs `colormap`, which the PxP applies to the updates sent with the `USE_CMAP`
+    /// flag. See `refresh::RefreshRequest::with_colormap(..)`. Colormaps reaching past the
+    /// `cmap::GRAY_LUT_SIZE` entries of the framebuffer are rejected.
+    ///
+    /// The PxP only uses the colormap in the 8-bit grayscale mode, so this fails with
+    /// `InvalidInput` unless `bits_per_pixel` is 8. The framebuffer is set up as 16-bit
+    /// RGB565 by default, in which colormaps don't do anything.
+    fn set_colormap(&self, colormap: &cmap::Colormap) -> std::io::Result<()>;
     /// Creates a FixScreeninfo struct and fills it using ioctl
-    fn get_fix_screeninfo(device: &std::fs::File) -> screeninfo::FixScreeninfo;
+    fn get_fix_screeninfo(device: &dyn device::FramebufferDevice) -> screeninfo::FixScreeninfo;
     /// Creates a VarScreeninfo struct and fills it using ioctl
-    fn get_var_screeninfo(device: &std::fs::File) -> screeninfo::VarScreeninfo;
+    fn get_var_screeninfo(device: &dyn device::FramebufferDevice) -> screeninfo::VarScreeninfo;
     /// Makes the proper ioctl call to set the VarScreenInfo.
     /// You must first update the contents of self.var_screen_info
     /// and then call this function.
     fn put_var_screeninfo(
-        device: &std::fs::File,
+        device: &dyn device::FramebufferDevice,
         var_screen_info: &mut screeninfo::VarScreeninfo,
     ) -> bool;
 }
 
 pub mod refresh;
 pub trait FramebufferRefresh {
-    /// Refreshes the entire screen with the provided parameters. If `wait_completion` is
-    /// set to true, doesn't return before the refresh has been completed. Returns the marker.
-    fn full_refresh(
+    /// Sends `request` to the EPDC after clipping its region to the screen, and waits for its
+    /// completion if the request asks for it. Fails if the region lies outside of the screen
+    /// or if the EPDC rejects the update.
+    fn submit_refresh(
         &self,
-        waveform_mode: common::waveform_mode,
-        temperature: common::display_temp,
-        dither_mode: common::dither_mode,
-        quant_bit: i32,
-        wait_completion: bool,
-    ) -> u32;
+        request: &refresh::RefreshRequest,
+    ) -> std::io::Result<refresh::RefreshResult>;
 
-    /// Refreshes the given `region` with the provided parameters. If `mode` is `DryRun` or
+    /// Refreshes the entire screen with the provided `profile`. If `wait_completion` is
+    /// set to true, doesn't return before the refresh has been completed. Returns the marker.
+    fn full_refresh(&self, profile: &refresh::RefreshProfile, wait_completion: bool) -> u32;
+
+    /// Refreshes the given `region` with the provided `profile`. If `mode` is `DryRun` or
     /// `Wait`, this function won't return before the `DryRun`'s collision_test or
     /// refresh has been completed. In `Async` mode, this function will return immediately
     /// and return a `marker` which can then later be fed to `wait_refresh_complete` to wait
@@ -153,19 +233,39 @@ pub trait FramebufferRefresh {
     ///    line than the original update line width, the EPDC would
     ///    cause screen artifacts by incorrectly handling the 8+ pixels
     ///    at the end of each line.
+    ///
+    /// `RefreshRequest::with_align(true)` grows the region to satisfy these constraints and
+    /// reports the region that was refreshed in the `RefreshResult`.
     fn partial_refresh(
         &self,
         region: &common::mxcfb_rect,
         mode: refresh::PartialRefreshMode,
-        waveform_mode: common::waveform_mode,
-        temperature: common::display_temp,
-        dither_mode: common::dither_mode,
-        quant_bit: i32,
+        profile: &refresh::RefreshProfile,
         force_full_refresh: bool,
     ) -> u32;
 
+    /// Issues an `Async` partial refresh with `profile` for each of the rects accumulated by
+    /// the `DamageTracker` of the framebuffer, and stops tracking them. Returns their markers.
+    fn flush_damage(&self, profile: &refresh::RefreshProfile) -> Vec<u32>;
+
+    /// Performs the ghosting cleanup the `RefreshScheduler` of the framebuffer considers due,
+    /// as `Async` full updates of the affected regions. Returns their markers.
+    fn run_scheduled_cleanup(&self) -> Vec<u32>;
+
+    /// Enables or disables displaying every update inverted, which the EPDC does as part of
+    /// the update at no cost. The framebuffer contents are left alone, so `read_pixel(..)`,
+    /// `dump_region(..)` and screenshots keep reporting the colors as drawn.
+    ///
+    /// Only the updates submitted afterwards are affected, follow up with a `full_refresh(..)`
+    /// to apply it to the whole screen.
+    fn set_night_mode(&self, enabled: bool);
+
+    /// Whether updates are currently displayed inverted, see `set_night_mode(..)`
+    fn night_mode(&self) -> bool;
+
     /// Takes a marker returned by `partial_refresh` and blocks until that
-    /// refresh has been reflected on the display.
+    /// refresh has been reflected on the display. The `CompletionService` of the
+    /// framebuffer waits for it on a background thread instead.
     /// Returns the collusion_test result which is supposed to be
     /// related to the collusion information.
     fn wait_refresh_complete(&self, marker: u32) -> u32;
@@ -3,7 +3,7 @@
 use libc;
 use libc::intptr_t;
 
-use framebuffer::common::mxcfb_rect;
+use framebuffer::common::{mxcfb_rect, waveform_mode};
 
 #[derive(Debug)]
 #[repr(C)]
@@ -36,7 +36,7 @@ impl ::std::default::Default for mxcfb_update_marker_data {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 #[repr(C)]
 pub struct mxcfb_alt_buffer_data {
     pub phys_addr: u32,
@@ -70,3 +70,42 @@ impl ::std::default::Default for mxcfb_update_data {
         unsafe { ::std::mem::zeroed() }
     }
 }
+
+/// The waveform modes the driver uses for each kind of update when it selects the
+/// waveform itself, as is the case for updates sent with `WAVEFORM_MODE_AUTO`
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct mxcfb_waveform_modes {
+    pub mode_init: i32,
+    pub mode_du: i32,
+    pub mode_gc4: i32,
+    pub mode_gc8: i32,
+    pub mode_gc16: i32,
+    pub mode_gc32: i32,
+}
+
+impl mxcfb_waveform_modes {
+    pub fn new(
+        mode_init: waveform_mode,
+        mode_du: waveform_mode,
+        mode_gc4: waveform_mode,
+        mode_gc8: waveform_mode,
+        mode_gc16: waveform_mode,
+        mode_gc32: waveform_mode,
+    ) -> mxcfb_waveform_modes {
+        mxcfb_waveform_modes {
+            mode_init: mode_init as i32,
+            mode_du: mode_du as i32,
+            mode_gc4: mode_gc4 as i32,
+            mode_gc8: mode_gc8 as i32,
+            mode_gc16: mode_gc16 as i32,
+            mode_gc32: mode_gc32 as i32,
+        }
+    }
+}
+
+impl ::std::default::Default for mxcfb_waveform_modes {
+    fn default() -> Self {
+        unsafe { ::std::mem::zeroed() }
+    }
+}
@@ -1,12 +1,15 @@
-use libc;
-
-use std::os::unix::io::AsRawFd;
 use std::sync::atomic::Ordering;
+use std::time::Instant;
 
 use framebuffer;
+use framebuffer::collision::CollisionPolicy;
 use framebuffer::common;
 use framebuffer::core;
+use framebuffer::damage::EPDC_BLOCK_SIZE;
 use framebuffer::mxcfb::*;
+use framebuffer::screeninfo::VarScreeninfo;
+use framebuffer::temperature::TemperatureMode;
+use framebuffer::FramebufferRefresh;
 
 pub enum PartialRefreshMode {
     DryRun,
@@ -14,75 +17,632 @@ pub enum PartialRefreshMode {
     Wait,
 }
 
-impl<'a> framebuffer::FramebufferRefresh for core::Framebuffer<'a> {
-    fn full_refresh(
-        &self,
+/// The waveform, temperature, dithering and quantization settings a refresh is performed with.
+///
+/// The associated consts cover the common cases. Custom profiles can be created with
+/// `RefreshProfile::new(..)` or by overriding some of the fields of a preset, e.g.
+/// `RefreshProfile { temperature: TemperatureMode::Auto, ..RefreshProfile::UI }`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RefreshProfile {
+    pub waveform_mode: common::waveform_mode,
+    pub temperature: TemperatureMode,
+    pub dither_mode: common::dither_mode,
+    pub quant_bit: i32,
+    /// If set, `waveform_mode` is ignored and the waveform is picked by looking at the pixels
+    /// of the refreshed region instead, unless the update comes from an alt buffer outside of
+    /// the pages of the framebuffer. See `select_waveform(..)`.
+    pub content_aware: bool,
+}
+
+impl RefreshProfile {
+    /// Lowest latency black and white updates for pen input, what `xochitl` uses for drawing
+    pub const INK: RefreshProfile = RefreshProfile {
+        waveform_mode: common::waveform_mode::WAVEFORM_MODE_DU,
+        temperature: TemperatureMode::Fixed(common::display_temp::TEMP_USE_REMARKABLE_DRAW),
+        dither_mode: common::dither_mode::EPDC_FLAG_EXP1,
+        quant_bit: common::DRAWING_QUANT_BIT,
+        content_aware: false,
+    };
+
+    /// Medium fidelity grayscale updates without flashing, what `xochitl` uses for its UI
+    pub const UI: RefreshProfile = RefreshProfile {
+        waveform_mode: common::waveform_mode::WAVEFORM_MODE_GC16_FAST,
+        temperature: TemperatureMode::Fixed(common::display_temp::TEMP_USE_REMARKABLE_DRAW),
+        dither_mode: common::dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
+        quant_bit: 0,
+        content_aware: false,
+    };
+
+    /// High fidelity grayscale updates for pictures
+    pub const IMAGE: RefreshProfile = RefreshProfile {
+        waveform_mode: common::waveform_mode::WAVEFORM_MODE_GC16,
+        temperature: TemperatureMode::Fixed(common::display_temp::TEMP_USE_PAPYRUS),
+        dither_mode: common::dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
+        quant_bit: 0,
+        content_aware: false,
+    };
+
+    /// Flashes the region to white to get rid of all ghosting
+    pub const CLEAN: RefreshProfile = RefreshProfile {
+        waveform_mode: common::waveform_mode::WAVEFORM_MODE_INIT,
+        temperature: TemperatureMode::Fixed(common::display_temp::TEMP_USE_AMBIENT),
+        dither_mode: common::dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
+        quant_bit: 0,
+        content_aware: false,
+    };
+
+    /// Fast black and white updates for UI elements, without the drawing specific dithering
+    pub const MONOCHROME: RefreshProfile = RefreshProfile {
+        waveform_mode: common::waveform_mode::WAVEFORM_MODE_DU,
+        temperature: TemperatureMode::Fixed(common::display_temp::TEMP_USE_REMARKABLE_DRAW),
+        dither_mode: common::dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
+        quant_bit: 0,
+        content_aware: false,
+    };
+
+    /// Grayscale updates whose waveform is picked by `select_waveform(..)` depending on
+    /// what the region holds, ranging from DU for black and white content to GC16 for pictures
+    pub const CONTENT: RefreshProfile = RefreshProfile {
+        content_aware: true,
+        ..RefreshProfile::UI
+    };
+
+    pub fn new(
         waveform_mode: common::waveform_mode,
-        temperature: common::display_temp,
+        temperature: TemperatureMode,
         dither_mode: common::dither_mode,
         quant_bit: i32,
-        wait_completion: bool,
-    ) -> u32 {
-        let screen = common::mxcfb_rect {
-            top: 0,
-            left: 0,
-            height: self.var_screen_info.yres,
-            width: self.var_screen_info.xres,
-        };
-        let marker = self.marker.fetch_add(1, Ordering::Relaxed);
-        let whole = mxcfb_update_data {
-            update_mode: common::update_mode::UPDATE_MODE_FULL as u32,
-            update_marker: marker as u32,
-            waveform_mode: waveform_mode as u32,
-            temp: temperature as i32,
-            flags: 0,
+    ) -> RefreshProfile {
+        RefreshProfile {
+            waveform_mode,
+            temperature,
+            dither_mode,
             quant_bit,
-            dither_mode: dither_mode as i32,
-            update_region: screen,
-            ..Default::default()
-        };
+            content_aware: false,
+        }
+    }
 
-        let pt: *const mxcfb_update_data = &whole;
-        unsafe {
-            libc::ioctl(self.device.as_raw_fd(), common::MXCFB_SEND_UPDATE, pt);
+    /// Looks up a preset by its case insensitive name, e.g. `"ink"` or `"UI"`
+    pub fn from_name(name: &str) -> Option<RefreshProfile> {
+        match name.to_lowercase().as_str() {
+            "ink" => Some(RefreshProfile::INK),
+            "ui" => Some(RefreshProfile::UI),
+            "image" => Some(RefreshProfile::IMAGE),
+            "clean" => Some(RefreshProfile::CLEAN),
+            "monochrome" => Some(RefreshProfile::MONOCHROME),
+            "content" => Some(RefreshProfile::CONTENT),
+            _ => None,
         }
+    }
+}
 
-        if wait_completion {
-            let mut markerdata = mxcfb_update_marker_data {
-                update_marker: whole.update_marker,
-                collision_test: 0,
+/// A fully specified refresh, exposing every field of `mxcfb_update_data`. Created with
+/// `RefreshRequest::new(..)`, configured through its `with_*` builder methods and sent to the
+/// EPDC with `submit(..)`, e.g.
+/// `RefreshRequest::new(rect).with_flags(EpdcFlags::FORCE_MONOCHROME).submit(&fb)`.
+#[derive(Copy, Clone, Debug)]
+pub struct RefreshRequest {
+    region: common::mxcfb_rect,
+    update_mode: common::update_mode,
+    profile: RefreshProfile,
+    flags: common::EpdcFlags,
+    marker: Option<u32>,
+    wait: bool,
+    align: bool,
+    collision_policy: Option<CollisionPolicy>,
+    alt_buffer_data: Option<mxcfb_alt_buffer_data>,
+}
+
+impl RefreshRequest {
+    /// A partial, asynchronous refresh of `region` using `RefreshProfile::UI` without any flags
+    pub fn new(region: common::mxcfb_rect) -> RefreshRequest {
+        RefreshRequest {
+            region,
+            update_mode: common::update_mode::UPDATE_MODE_PARTIAL,
+            profile: RefreshProfile::UI,
+            flags: common::EpdcFlags::empty(),
+            marker: None,
+            wait: false,
+            align: false,
+            collision_policy: None,
+            alt_buffer_data: None,
+        }
+    }
+
+    pub fn with_region(mut self, region: common::mxcfb_rect) -> RefreshRequest {
+        self.region = region;
+        self
+    }
+
+    pub fn with_update_mode(mut self, update_mode: common::update_mode) -> RefreshRequest {
+        self.update_mode = update_mode;
+        self
+    }
+
+    /// Sets the waveform, temperature, dither mode and quant bit at once
+    pub fn with_profile(mut self, profile: &RefreshProfile) -> RefreshRequest {
+        self.profile = *profile;
+        self
+    }
+
+    pub fn with_waveform_mode(mut self, waveform_mode: common::waveform_mode) -> RefreshRequest {
+        self.profile.waveform_mode = waveform_mode;
+        self
+    }
+
+    /// Takes a `TemperatureMode` or one of the `display_temp` values
+    pub fn with_temperature<T: Into<TemperatureMode>>(mut self, temperature: T) -> RefreshRequest {
+        self.profile.temperature = temperature.into();
+        self
+    }
+
+    pub fn with_dither_mode(mut self, dither_mode: common::dither_mode) -> RefreshRequest {
+        self.profile.dither_mode = dither_mode;
+        self
+    }
+
+    pub fn with_quant_bit(mut self, quant_bit: i32) -> RefreshRequest {
+        self.profile.quant_bit = quant_bit;
+        self
+    }
+
+    pub fn with_content_aware(mut self, content_aware: bool) -> RefreshRequest {
+        self.profile.content_aware = content_aware;
+        self
+    }
+
+    /// Replaces the flags. `USE_ALT_BUFFER` is set automatically by `with_alt_buffer(..)`,
+    /// and `USE_CMAP` by `with_colormap(..)`.
+    pub fn with_flags(mut self, flags: common::EpdcFlags) -> RefreshRequest {
+        self.flags = flags;
+        self
+    }
+
+    /// Uses `marker` instead of the next one from the counter of the framebuffer
+    pub fn with_marker(mut self, marker: u32) -> RefreshRequest {
+        self.marker = Some(marker);
+        self
+    }
+
+    /// Whether `submit(..)` blocks until the refresh has been completed
+    pub fn with_wait(mut self, wait: bool) -> RefreshRequest {
+        self.wait = wait;
+        self
+    }
+
+    /// Whether the region gets grown to the 8x8 pixel blocks of the EPDC and to the 32-bit
+    /// alignment of the PxP input before being sent. See `align_region(..)`.
+    pub fn with_align(mut self, align: bool) -> RefreshRequest {
+        self.align = align;
+        self
+    }
+
+    /// How to handle the region overlapping updates that are still in flight. By default
+    /// the request is submitted regardless. Ignored for collision tests.
+    pub fn with_collision_policy(mut self, policy: CollisionPolicy) -> RefreshRequest {
+        self.collision_policy = Some(policy);
+        self
+    }
+
+    /// Whether the PxP remaps the gray levels of the region through the colormap of the
+    /// framebuffer, installed with `set_colormap(..)`. Sets or clears the `USE_CMAP` flag.
+    ///
+    /// Only the 8-bit grayscale mode has a colormap, so submitting the request fails with
+    /// `InvalidInput` if the framebuffer uses any other `bits_per_pixel`, like the default
+    /// 16-bit RGB565.
+    pub fn with_colormap(mut self, use_colormap: bool) -> RefreshRequest {
+        self.flags.set(common::EpdcFlags::USE_CMAP, use_colormap);
+        self
+    }
+
+    /// Refreshes from `alt_buffer_data` instead of the framebuffer, which needs
+    /// to lie within the framebuffer memory. Sets the `USE_ALT_BUFFER` flag.
+    pub fn with_alt_buffer(mut self, alt_buffer_data: mxcfb_alt_buffer_data) -> RefreshRequest {
+        self.alt_buffer_data = Some(alt_buffer_data);
+        self.flags.insert(common::EpdcFlags::USE_ALT_BUFFER);
+        self
+    }
+
+    pub fn region(&self) -> common::mxcfb_rect {
+        self.region
+    }
+
+    pub fn update_mode(&self) -> common::update_mode {
+        self.update_mode
+    }
+
+    pub fn profile(&self) -> RefreshProfile {
+        self.profile
+    }
+
+    pub fn flags(&self) -> common::EpdcFlags {
+        self.flags
+    }
+
+    pub fn wait(&self) -> bool {
+        self.wait
+    }
+
+    pub fn align(&self) -> bool {
+        self.align
+    }
+
+    /// The marker set with `with_marker(..)`, if any
+    pub fn marker(&self) -> Option<u32> {
+        self.marker
+    }
+
+    pub fn collision_policy(&self) -> Option<CollisionPolicy> {
+        self.collision_policy
+    }
+
+    pub fn alt_buffer(&self) -> Option<mxcfb_alt_buffer_data> {
+        self.alt_buffer_data
+    }
+
+    pub fn submit<F: framebuffer::FramebufferRefresh>(
+        &self,
+        fb: &F,
+    ) -> ::std::io::Result<RefreshResult> {
+        fb.submit_refresh(self)
+    }
+
+    /// Builds the `mxcfb_update_data` for `region` tagged with `marker`, sent with the
+    /// waveform and raw temperature value resolved by the `TemperatureSensor`
+    fn to_update_data(
+        &self,
+        region: common::mxcfb_rect,
+        marker: u32,
+        waveform_mode: common::waveform_mode,
+        temp: i32,
+    ) -> mxcfb_update_data {
+        mxcfb_update_data {
+            update_region: region,
+            waveform_mode: waveform_mode as u32,
+            update_mode: self.update_mode as u32,
+            update_marker: marker,
+            temp,
+            flags: self.flags.bits(),
+            dither_mode: self.profile.dither_mode as i32,
+            quant_bit: self.profile.quant_bit,
+            alt_buffer_data: self.alt_buffer_data.unwrap_or_default(),
+        }
+    }
+}
+
+/// Maps `region` from framebuffer coordinates to panel coordinates, undoing the rotation
+/// the framebuffer is set up with. Applying it with `to_panel` false maps it back.
+fn rotate_region(
+    region: &common::mxcfb_rect,
+    var_screen_info: &VarScreeninfo,
+    to_panel: bool,
+) -> common::mxcfb_rect {
+    let (xres, yres) = (var_screen_info.xres, var_screen_info.yres);
+    let r = *region;
+    match (var_screen_info.rotate % 4, to_panel) {
+        // FB_ROTATE_CW
+        (1, true) => common::mxcfb_rect {
+            top: r.left,
+            left: yres - (r.top + r.height),
+            width: r.height,
+            height: r.width,
+        },
+        (1, false) => common::mxcfb_rect {
+            top: yres - (r.left + r.width),
+            left: r.top,
+            width: r.height,
+            height: r.width,
+        },
+        // FB_ROTATE_UD
+        (2, _) => common::mxcfb_rect {
+            top: yres - (r.top + r.height),
+            left: xres - (r.left + r.width),
+            width: r.width,
+            height: r.height,
+        },
+        // FB_ROTATE_CCW
+        (3, true) => common::mxcfb_rect {
+            top: xres - (r.left + r.width),
+            left: r.top,
+            width: r.height,
+            height: r.width,
+        },
+        (3, false) => common::mxcfb_rect {
+            top: r.left,
+            left: xres - (r.top + r.height),
+            width: r.height,
+            height: r.width,
+        },
+        _ => r,
+    }
+}
+
+/// Grows `start..start + len` outwards to multiples of `alignment`, without going past `max`
+fn align_span(start: u32, len: u32, alignment: u32, max: u32) -> (u32, u32) {
+    let end = ::std::cmp::min((start + len + alignment - 1) / alignment * alignment, max);
+    let start = start / alignment * alignment;
+    (start, end - start)
+}
+
+/// Grows `region`, which needs to lie within the screen, to the constraints described on
+/// `FramebufferRefresh::partial_refresh(..)`: whole 8x8 pixel blocks of the panel, and a
+/// horizontal extent in the framebuffer memory made of whole 32-bit words. The blocks are
+/// those of the panel, so the rotation of the framebuffer is taken into account, while the
+/// number of pixels per word depends on its bits per pixel.
+pub fn align_region(
+    region: &common::mxcfb_rect,
+    var_screen_info: &VarScreeninfo,
+) -> common::mxcfb_rect {
+    let panel = rotate_region(region, var_screen_info, true);
+    let (panel_width, panel_height) = if var_screen_info.rotate % 2 == 1 {
+        (var_screen_info.yres, var_screen_info.xres)
+    } else {
+        (var_screen_info.xres, var_screen_info.yres)
+    };
+    let (left, width) = align_span(panel.left, panel.width, EPDC_BLOCK_SIZE, panel_width);
+    let (top, height) = align_span(panel.top, panel.height, EPDC_BLOCK_SIZE, panel_height);
+    let mut aligned = rotate_region(
+        &common::mxcfb_rect {
+            top,
+            left,
+            width,
+            height,
+        },
+        var_screen_info,
+        false,
+    );
+
+    let pixels_per_word =
+        ::std::cmp::max(32 / ::std::cmp::max(var_screen_info.bits_per_pixel, 1), 1);
+    let (left, width) = align_span(
+        aligned.left,
+        aligned.width,
+        pixels_per_word,
+        var_screen_info.xres,
+    );
+    aligned.left = left;
+    aligned.width = width;
+    aligned
+}
+
+/// Regions that are at least this white get GL16, which is meant for content on white
+const GL16_MIN_WHITE_PERCENT: u64 = 75;
+/// Regions with at least this many gray levels are considered pictures and get GC16
+const GC16_MIN_GRAY_LEVELS: u32 = 12;
+
+/// The outcome of `select_waveform(..)`, along with what it was based on
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WaveformSelection {
+    pub waveform_mode: common::waveform_mode,
+    /// Number of sampled pixels of the region at each of the 16 gray levels the EPDC works
+    /// with, from black to white
+    pub histogram: [u64; 16],
+}
+
+impl WaveformSelection {
+    /// Number of distinct gray levels present in the region
+    pub fn gray_levels(&self) -> u32 {
+        self.histogram.iter().filter(|&&count| count > 0).count() as u32
+    }
+}
+
+/// Picks the waveform for refreshing `region` of `fb` from a histogram of its gray levels, the
+/// way the PxP does for `WAVEFORM_MODE_AUTO`, but with a documented outcome:
+///
+///  - DU if the region only holds black and white,
+///  - GC16 if it holds at least `GC16_MIN_GRAY_LEVELS` gray levels, like pictures do,
+///  - GL16 if it is mostly white, like anti-aliased text is,
+///  - GC16_FAST otherwise.
+///
+/// Like the PxP, it samples the center pixel of every 8x8 block of the EPDC the region
+/// touches rather than reading all of its pixels. The pixels are read from the displayed
+/// page, the one the EPDC refreshes from, rather than from the draw page.
+pub fn select_waveform<'a>(
+    fb: &core::Framebuffer<'a>,
+    region: &common::mxcfb_rect,
+) -> WaveformSelection {
+    select_waveform_on_page(fb, fb.displayed_page(), region)
+}
+
+/// Like `select_waveform(..)`, but samples `page` of the framebuffer instead of the displayed
+/// one. Panics if `page` is not below `page_count()`.
+pub fn select_waveform_on_page<'a>(
+    fb: &core::Framebuffer<'a>,
+    page: u32,
+    region: &common::mxcfb_rect,
+) -> WaveformSelection {
+    let mut histogram = [0u64; 16];
+    let mut gray = [0u8; 1];
+    let (right, bottom) = (region.left + region.width, region.top + region.height);
+    let center = EPDC_BLOCK_SIZE / 2;
+    let mut block_top = region.top / EPDC_BLOCK_SIZE * EPDC_BLOCK_SIZE;
+    while block_top < bottom {
+        let y = ::std::cmp::min(::std::cmp::max(block_top + center, region.top), bottom - 1);
+        let mut block_left = region.left / EPDC_BLOCK_SIZE * EPDC_BLOCK_SIZE;
+        while block_left < right {
+            let x = ::std::cmp::min(::std::cmp::max(block_left + center, region.left), right - 1);
+            let pixel = common::mxcfb_rect {
+                top: y,
+                left: x,
+                width: 1,
+                height: 1,
             };
-            unsafe {
-                if libc::ioctl(
-                    self.device.as_raw_fd(),
-                    common::MXCFB_WAIT_FOR_UPDATE_COMPLETE,
-                    &mut markerdata,
-                ) < 0
-                {
-                    warn!("WAIT_FOR_UPDATE_COMPLETE failed after a full_refresh(..)");
+            fb.read_gray_row(page, &pixel, 0, &mut gray);
+            histogram[(gray[0] >> 4) as usize] += 1;
+            block_left += EPDC_BLOCK_SIZE;
+        }
+        block_top += EPDC_BLOCK_SIZE;
+    }
+
+    let total: u64 = histogram.iter().sum();
+    let levels = histogram.iter().filter(|&&count| count > 0).count() as u32;
+    let waveform_mode = if histogram[0] + histogram[15] == total {
+        common::waveform_mode::WAVEFORM_MODE_DU
+    } else if levels >= GC16_MIN_GRAY_LEVELS {
+        common::waveform_mode::WAVEFORM_MODE_GC16
+    } else if histogram[15] * 100 >= total * GL16_MIN_WHITE_PERCENT {
+        common::waveform_mode::WAVEFORM_MODE_GL16_FAST
+    } else {
+        common::waveform_mode::WAVEFORM_MODE_GC16_FAST
+    };
+    WaveformSelection {
+        waveform_mode,
+        histogram,
+    }
+}
+
+/// What happened to a submitted `RefreshRequest`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RefreshResult {
+    /// The marker the update was tagged with, to be passed to `wait_refresh_complete(..)`
+    pub marker: u32,
+    /// The region that was actually sent, after clipping it to the screen and aligning
+    /// it if the request asked for it
+    pub region: common::mxcfb_rect,
+    /// The `collision_test` result of the update if the request waited for its completion
+    pub collision_test: Option<u32>,
+    /// The waveform that was used, which content-aware selection and
+    /// `TemperatureMode::Auto` may have changed
+    pub waveform_mode: common::waveform_mode,
+    /// How the waveform was picked, if the profile asked for content-aware selection
+    pub selection: Option<WaveformSelection>,
+    /// The raw temperature value that was sent, in degrees Celsius unless it is one of
+    /// the `display_temp` values
+    pub temperature: i32,
+    /// Number of in-flight updates the driver reported the region colliding with, which have
+    /// been handled according to the `CollisionPolicy` of the request
+    pub collisions: u32,
+}
+
+/// Sends `region` as a collision test with the settings of `request` and returns whether
+/// the driver reports it colliding with an update in flight
+fn driver_reports_collision<'a>(
+    fb: &core::Framebuffer<'a>,
+    request: &RefreshRequest,
+    region: &common::mxcfb_rect,
+) -> ::std::io::Result<bool> {
+    let marker = fb.marker.fetch_add(1, Ordering::Relaxed);
+    let (temperature, waveform_mode) = fb
+        .temperature
+        .resolve(request.profile.temperature, request.profile.waveform_mode);
+    let mut test = request.to_update_data(*region, marker, waveform_mode, temperature);
+    test.flags |= common::EPDC_FLAG_TEST_COLLISION;
+    fb.checked_ioctl(common::MXCFB_SEND_UPDATE, &mut test)?;
+    let mut markerdata = mxcfb_update_marker_data {
+        update_marker: marker,
+        collision_test: 0,
+    };
+    fb.checked_ioctl(common::MXCFB_WAIT_FOR_UPDATE_COMPLETE, &mut markerdata)?;
+    Ok(markerdata.collision_test != 0)
+}
+
+/// Applies `policy` if the driver reports `region` colliding with updates in flight,
+/// growing `region` for `CollisionPolicy::Merge`. Returns the number of updates it
+/// collided with, which is at least 1 on a collision even if none of them were submitted
+/// through `fb` and there is nothing to wait for.
+fn handle_collisions<'a>(
+    fb: &core::Framebuffer<'a>,
+    request: &RefreshRequest,
+    policy: CollisionPolicy,
+    region: &mut common::mxcfb_rect,
+) -> ::std::io::Result<u32> {
+    if !driver_reports_collision(fb, request, region)? {
+        // Nothing overlapping the region is in flight anymore
+        fb.in_flight.complete_overlapping(region);
+        return Ok(0);
+    }
+    let colliding: Vec<u32> = match policy {
+        CollisionPolicy::Drop => {
+            return Err(::std::io::Error::new(
+                ::std::io::ErrorKind::WouldBlock,
+                "The update region collides with an update in flight",
+            ))
+        }
+        CollisionPolicy::WaitThenSubmit => fb
+            .in_flight
+            .colliding(region)
+            .iter()
+            .map(|&(marker, _)| marker)
+            .collect(),
+        CollisionPolicy::Merge => {
+            // The grown region may run into further updates, so repeat until no new
+            // marker turns up
+            let mut merged: Vec<u32> = Vec::new();
+            loop {
+                let new: Vec<(u32, common::mxcfb_rect)> = fb
+                    .in_flight
+                    .colliding(region)
+                    .into_iter()
+                    .filter(|&(marker, _)| !merged.contains(&marker))
+                    .collect();
+                if new.is_empty() {
+                    break;
+                }
+                for (marker, other) in new {
+                    *region = region.merge_rect(&other);
+                    merged.push(marker);
+                }
+                if request.align {
+                    *region = align_region(region, &fb.var_screen_info);
                 }
             }
+            merged
         }
-        whole.update_marker
+    };
+    if colliding.is_empty() {
+        debug!("{:?} collides with updates submitted elsewhere", region);
     }
+    for &marker in &colliding {
+        fb.wait_refresh_complete(marker);
+    }
+    Ok(::std::cmp::max(colliding.len() as u32, 1))
+}
 
-    fn partial_refresh(
-        &self,
-        region: &common::mxcfb_rect,
-        mode: PartialRefreshMode,
-        waveform_mode: common::waveform_mode,
-        temperature: common::display_temp,
-        dither_mode: common::dither_mode,
-        quant_bit: i32,
-        force_full_refresh: bool,
-    ) -> u32 {
-        let mut update_region = region.to_owned();
+/// Whether `region` starts past the edges of the screen, leaving nothing to refresh
+fn outside_of_screen(region: &common::mxcfb_rect) -> bool {
+    region.left >= u32::from(common::DISPLAYWIDTH) || region.top >= u32::from(common::DISPLAYHEIGHT)
+}
 
-        // No accounting for this, out of bounds, entirely ignored
-        if update_region.left >= u32::from(common::DISPLAYWIDTH)
-            || update_region.top >= u32::from(common::DISPLAYHEIGHT)
+/// The page of `fb` that `alt_buffer_data` points at, if it starts at one and is laid out like
+/// the screen with its update region on it
+fn alt_buffer_page<'a>(
+    fb: &core::Framebuffer<'a>,
+    alt_buffer_data: &mxcfb_alt_buffer_data,
+) -> Option<u32> {
+    let page_length = fb.page_length();
+    let offset = (alt_buffer_data.phys_addr as usize).checked_sub(fb.fix_screen_info.smem_start)?;
+    let line_pixels = fb.fix_screen_info.line_length * 8 / fb.var_screen_info.bits_per_pixel;
+    if page_length == 0
+        || offset % page_length != 0
+        || offset / page_length >= fb.page_count() as usize
+        || alt_buffer_data.width != line_pixels
+        || alt_buffer_data.height != fb.var_screen_info.yres
+        || fb.check_region(&alt_buffer_data.alt_update_region).is_err()
+    {
+        return None;
+    }
+    Some((offset / page_length) as u32)
+}
+
+impl<'a> framebuffer::FramebufferRefresh for core::Framebuffer<'a> {
+    fn submit_refresh(&self, request: &RefreshRequest) -> ::std::io::Result<RefreshResult> {
+        let mut update_region = request.region;
+
+        if request.flags.contains(common::EpdcFlags::USE_CMAP)
+            && self.var_screen_info.bits_per_pixel != 8
         {
-            return 0;
+            return Err(::std::io::Error::new(
+                ::std::io::ErrorKind::InvalidInput,
+                "The PxP only applies the colormap in the 8-bit grayscale mode",
+            ));
+        }
+
+        // No accounting for this, out of bounds, entirely ignored
+        if outside_of_screen(&update_region) {
+            return Err(::std::io::Error::new(
+                ::std::io::ErrorKind::InvalidInput,
+                "The update region lies outside of the screen",
+            ));
         }
 
         if update_region.width < 1 {
@@ -104,70 +664,209 @@ impl<'a> framebuffer::FramebufferRefresh for core::Framebuffer<'a> {
             update_region.height -= max_y - u32::from(common::DISPLAYHEIGHT);
         }
 
-        let update_mode = if force_full_refresh {
-            common::update_mode::UPDATE_MODE_FULL as u32
-        } else {
-            common::update_mode::UPDATE_MODE_PARTIAL as u32
+        if request.align {
+            update_region = align_region(&update_region, &self.var_screen_info);
+        }
+
+        let test_collision = request.flags.contains(common::EpdcFlags::TEST_COLLISION);
+        let collisions = match (request.collision_policy, test_collision) {
+            (Some(policy), false) => handle_collisions(self, request, policy, &mut update_region)?,
+            _ => 0,
         };
 
-        let marker = self.marker.fetch_add(1, Ordering::Relaxed);
-        let whole = mxcfb_update_data {
-            update_mode,
-            update_marker: marker as u32,
-            waveform_mode: waveform_mode as u32,
-            temp: temperature as i32,
-            flags: match mode {
-                PartialRefreshMode::DryRun => common::EPDC_FLAG_TEST_COLLISION as u32,
-                _ => 0,
-            },
-            quant_bit,
-            dither_mode: dither_mode as i32,
-            update_region,
-            ..Default::default()
+        let marker = match request.marker {
+            Some(marker) => marker,
+            None => self.marker.fetch_add(1, Ordering::Relaxed),
         };
+        // The selection looks at the pixels the update is sent from, which for an alt buffer
+        // is only known when it is one of the pages of the framebuffer
+        let source = if request.flags.contains(common::EpdcFlags::USE_ALT_BUFFER) {
+            request.alt_buffer_data.and_then(|data| {
+                alt_buffer_page(self, &data).map(|page| (page, data.alt_update_region))
+            })
+        } else {
+            Some((self.displayed_page(), update_region))
+        };
+        let selection = match source {
+            Some((page, region)) if request.profile.content_aware => {
+                let selection = select_waveform_on_page(self, page, &region);
+                debug!(
+                    "Picked {:?} for {:?} holding {} gray levels",
+                    selection.waveform_mode,
+                    update_region,
+                    selection.gray_levels()
+                );
+                Some(selection)
+            }
+            _ => None,
+        };
+        let waveform_mode = match selection {
+            Some(ref selection) => selection.waveform_mode,
+            None => request.profile.waveform_mode,
+        };
+        let (temperature, waveform_mode) = self
+            .temperature
+            .resolve(request.profile.temperature, waveform_mode);
+        let mut whole = request.to_update_data(update_region, marker, waveform_mode, temperature);
+        if self.night_mode() {
+            whole.flags |= common::EPDC_FLAG_ENABLE_INVERSION;
+        }
 
-        let pt: *const mxcfb_update_data = &whole;
-        unsafe {
-            libc::ioctl(self.device.as_raw_fd(), common::MXCFB_SEND_UPDATE, pt);
+        let submitted_at = Instant::now();
+        self.checked_ioctl(common::MXCFB_SEND_UPDATE, &mut whole)?;
+        self.telemetry.record_submit(
+            whole.update_marker,
+            &update_region,
+            waveform_mode,
+            submitted_at,
+            submitted_at.elapsed(),
+        );
+        // A collision test doesn't change what is on the screen, and neither does an update
+        // the EPDC rejected, which leaves the damage and the ghosting as they were
+        if !test_collision {
+            self.damage.clear_region(&update_region);
+            self.scheduler.record(
+                &update_region,
+                waveform_mode,
+                request.update_mode == common::update_mode::UPDATE_MODE_FULL,
+            );
+            self.in_flight.add(whole.update_marker, update_region);
         }
 
-        match mode {
-            PartialRefreshMode::Wait | PartialRefreshMode::DryRun => {
-                let mut markerdata = mxcfb_update_marker_data {
-                    update_marker: whole.update_marker,
-                    collision_test: 0,
-                };
-                unsafe {
-                    if libc::ioctl(
-                        self.device.as_raw_fd(),
-                        common::MXCFB_WAIT_FOR_UPDATE_COMPLETE,
-                        &mut markerdata,
-                    ) < 0
-                    {
-                        warn!("WAIT_FOR_UPDATE_COMPLETE failed after a partial_refresh(..)");
+        let collision_test = if request.wait {
+            let mut markerdata = mxcfb_update_marker_data {
+                update_marker: whole.update_marker,
+                collision_test: 0,
+            };
+            let collision_test =
+                match self.checked_ioctl(common::MXCFB_WAIT_FOR_UPDATE_COMPLETE, &mut markerdata) {
+                    Ok(()) => Some(markerdata.collision_test),
+                    Err(e) => {
+                        warn!(
+                            "WAIT_FOR_UPDATE_COMPLETE failed after submitting a refresh: {}",
+                            e
+                        );
+                        None
                     }
-                }
-                markerdata.collision_test
+                };
+            self.in_flight.complete(whole.update_marker);
+            self.telemetry
+                .record_completion(whole.update_marker, collision_test);
+            collision_test
+        } else {
+            None
+        };
+
+        Ok(RefreshResult {
+            marker: whole.update_marker,
+            region: update_region,
+            collision_test,
+            waveform_mode,
+            selection,
+            temperature,
+            collisions,
+        })
+    }
+
+    fn full_refresh(&self, profile: &RefreshProfile, wait_completion: bool) -> u32 {
+        let screen = common::mxcfb_rect {
+            top: 0,
+            left: 0,
+            height: self.var_screen_info.yres,
+            width: self.var_screen_info.xres,
+        };
+        let request = RefreshRequest::new(screen)
+            .with_update_mode(common::update_mode::UPDATE_MODE_FULL)
+            .with_profile(profile)
+            .with_wait(wait_completion);
+        match self.submit_refresh(&request) {
+            Ok(result) => result.marker,
+            Err(e) => {
+                warn!("full_refresh(..) failed: {}", e);
+                0
             }
-            PartialRefreshMode::Async => whole.update_marker,
         }
     }
 
+    fn partial_refresh(
+        &self,
+        region: &common::mxcfb_rect,
+        mode: PartialRefreshMode,
+        profile: &RefreshProfile,
+        force_full_refresh: bool,
+    ) -> u32 {
+        // Out of bounds regions are silently ignored
+        if outside_of_screen(region) {
+            return 0;
+        }
+        let request = RefreshRequest::new(*region)
+            .with_update_mode(if force_full_refresh {
+                common::update_mode::UPDATE_MODE_FULL
+            } else {
+                common::update_mode::UPDATE_MODE_PARTIAL
+            })
+            .with_profile(profile);
+        let request = match mode {
+            PartialRefreshMode::DryRun => request
+                .with_flags(common::EpdcFlags::TEST_COLLISION)
+                .with_wait(true),
+            PartialRefreshMode::Wait => request.with_wait(true),
+            PartialRefreshMode::Async => request,
+        };
+
+        match self.submit_refresh(&request) {
+            Ok(result) => match mode {
+                PartialRefreshMode::Async => result.marker,
+                _ => result.collision_test.unwrap_or(0),
+            },
+            Err(e) => {
+                warn!("partial_refresh(..) failed: {}", e);
+                0
+            }
+        }
+    }
+
+    fn flush_damage(&self, profile: &RefreshProfile) -> Vec<u32> {
+        self.damage
+            .take()
+            .iter()
+            .map(|rect| self.partial_refresh(rect, PartialRefreshMode::Async, profile, false))
+            .collect()
+    }
+
+    fn run_scheduled_cleanup(&self) -> Vec<u32> {
+        let profile = self.scheduler.config().cleanup_profile;
+        self.scheduler
+            .take_due()
+            .iter()
+            .map(|rect| self.partial_refresh(rect, PartialRefreshMode::Async, &profile, true))
+            .collect()
+    }
+
+    fn set_night_mode(&self, enabled: bool) {
+        self.night_mode.store(enabled, Ordering::Relaxed);
+    }
+
+    fn night_mode(&self) -> bool {
+        self.night_mode.load(Ordering::Relaxed)
+    }
+
     fn wait_refresh_complete(&self, marker: u32) -> u32 {
         let mut markerdata = mxcfb_update_marker_data {
             update_marker: marker,
             collision_test: 0,
         };
-        unsafe {
-            if libc::ioctl(
-                self.device.as_raw_fd(),
-                common::MXCFB_WAIT_FOR_UPDATE_COMPLETE,
-                &mut markerdata,
-            ) < 0
-            {
-                warn!("WAIT_FOR_UPDATE_COMPLETE failed");
+        let result = self.checked_ioctl(common::MXCFB_WAIT_FOR_UPDATE_COMPLETE, &mut markerdata);
+        self.in_flight.complete(marker);
+        match result {
+            Ok(()) => self
+                .telemetry
+                .record_completion(marker, Some(markerdata.collision_test)),
+            Err(e) => {
+                warn!("WAIT_FOR_UPDATE_COMPLETE failed: {}", e);
+                self.telemetry.record_completion(marker, None);
             }
-        };
+        }
         markerdata.collision_test
     }
 }
@@ -0,0 +1,255 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use framebuffer::cgmath;
+use framebuffer::common::{mxcfb_rect, waveform_mode};
+use framebuffer::damage::{dirty_blocks_to_rects, EPDC_BLOCK_SIZE};
+use framebuffer::refresh::RefreshProfile;
+
+/// Settings of the `RefreshScheduler`
+#[derive(Copy, Clone, Debug)]
+pub struct SchedulerConfig {
+    /// A disabled scheduler neither counts updates nor schedules any cleanup
+    pub enabled: bool,
+    /// Side of the square tiles the screen is divided into, in pixels.
+    /// Rounded up to a multiple of the 8x8 pixel blocks of the EPDC.
+    pub tile_size: u32,
+    /// Number of fast updates a tile can take before it gets cleaned up
+    pub fast_update_threshold: u32,
+    /// If set, the tiles that took any fast update get cleaned up once there
+    /// hasn't been a fast update anywhere on the screen for this long
+    pub idle_timeout: Option<Duration>,
+    /// The cleanup is a full update of the tiles performed with this profile
+    pub cleanup_profile: RefreshProfile,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> SchedulerConfig {
+        SchedulerConfig {
+            enabled: false,
+            tile_size: 128,
+            fast_update_threshold: 32,
+            idle_timeout: Some(Duration::from_secs(3)),
+            cleanup_profile: RefreshProfile {
+                waveform_mode: waveform_mode::WAVEFORM_MODE_GC16,
+                ..RefreshProfile::CLEAN
+            },
+        }
+    }
+}
+
+/// Waveforms that only drive some of the transitions and leave ghosting behind
+fn is_fast(waveform: waveform_mode) -> bool {
+    match waveform {
+        waveform_mode::WAVEFORM_MODE_DU
+        | waveform_mode::WAVEFORM_MODE_DU4
+        | waveform_mode::WAVEFORM_MODE_GLR16
+        | waveform_mode::WAVEFORM_MODE_GLD16
+        | waveform_mode::WAVEFORM_MODE_GL4 => true,
+        _ => false,
+    }
+}
+
+/// Waveforms that clear the ghosting of the region when used for a full update
+fn is_cleaning(waveform: waveform_mode) -> bool {
+    match waveform {
+        waveform_mode::WAVEFORM_MODE_INIT | waveform_mode::WAVEFORM_MODE_GC16 => true,
+        _ => false,
+    }
+}
+
+struct Tiles {
+    config: SchedulerConfig,
+    bounds: cgmath::Vector2<u32>,
+    tile_size: u32,
+    cols: u32,
+    rows: u32,
+    /// Fast updates each tile took since it was last cleaned up
+    counts: Vec<u32>,
+    last_fast_update: Option<Instant>,
+}
+
+impl Tiles {
+    fn new(bounds: cgmath::Vector2<u32>, config: SchedulerConfig) -> Tiles {
+        let tile_size = ::std::cmp::max(
+            // Tiles are made of whole EPDC blocks
+            (config.tile_size + EPDC_BLOCK_SIZE - 1) / EPDC_BLOCK_SIZE * EPDC_BLOCK_SIZE,
+            EPDC_BLOCK_SIZE,
+        );
+        let cols = (bounds.x + tile_size - 1) / tile_size;
+        let rows = (bounds.y + tile_size - 1) / tile_size;
+        Tiles {
+            config,
+            bounds,
+            tile_size,
+            cols,
+            rows,
+            counts: vec![0; (cols * rows) as usize],
+            last_fast_update: None,
+        }
+    }
+
+    /// Returns the (first col, last col, first row, last row) of the tiles `rect` touches
+    fn touched(&self, rect: &mxcfb_rect) -> Option<(u32, u32, u32, u32)> {
+        if rect.width == 0 || rect.height == 0 {
+            return None;
+        }
+        let size = self.tile_size;
+        let first_col = rect.left / size;
+        let first_row = rect.top / size;
+        if first_col >= self.cols || first_row >= self.rows {
+            return None;
+        }
+        let last_col = ::std::cmp::min((rect.left + rect.width - 1) / size, self.cols - 1);
+        let last_row = ::std::cmp::min((rect.top + rect.height - 1) / size, self.rows - 1);
+        Some((first_col, last_col, first_row, last_row))
+    }
+
+    /// Returns true if `rect` covers all of the tile, which may be cut off by the screen edge
+    fn covers(&self, rect: &mxcfb_rect, col: u32, row: u32) -> bool {
+        let size = self.tile_size;
+        let right = ::std::cmp::min((col + 1) * size, self.bounds.x);
+        let bottom = ::std::cmp::min((row + 1) * size, self.bounds.y);
+        rect.left <= col * size
+            && rect.top <= row * size
+            && rect.left + rect.width >= right
+            && rect.top + rect.height >= bottom
+    }
+}
+
+/// Keeps track of how many fast (DU, A2 and the like) partial updates each tile of the screen
+/// took, and schedules a cleaning full update for the tiles that accumulated ghosting.
+///
+/// A tile is due for a cleanup once it took `fast_update_threshold` fast updates, or once the
+/// screen has been idle for `idle_timeout`. While a pen stroke is in progress nothing is
+/// scheduled so that the cleanup doesn't flash underneath the pen, the tiles that became due
+/// in the meantime get cleaned up after the stroke ends.
+///
+/// The framebuffer records every `partial_refresh(..)` and `full_refresh(..)` automatically.
+/// `FramebufferRefresh::run_scheduled_cleanup(..)` performs the cleanup that is due, and is
+/// called periodically by `ApplicationContext::dispatch_events(..)`.
+pub struct RefreshScheduler {
+    bounds: cgmath::Vector2<u32>,
+    stroke_active: AtomicBool,
+    tiles: Mutex<Tiles>,
+}
+
+impl RefreshScheduler {
+    pub fn new(bounds: cgmath::Vector2<u32>, config: SchedulerConfig) -> RefreshScheduler {
+        RefreshScheduler {
+            bounds,
+            stroke_active: AtomicBool::new(false),
+            tiles: Mutex::new(Tiles::new(bounds, config)),
+        }
+    }
+
+    pub fn config(&self) -> SchedulerConfig {
+        self.tiles.lock().unwrap().config
+    }
+
+    /// Replaces the config and forgets the updates counted so far
+    pub fn set_config(&self, config: SchedulerConfig) {
+        *self.tiles.lock().unwrap() = Tiles::new(self.bounds, config);
+    }
+
+    /// Records a refresh of `rect` performed with `waveform`. Fast waveforms count towards the
+    /// cleanup of the tiles they touch, while cleaning waveforms used for a `full_update` reset
+    /// the tiles they entirely cover.
+    pub fn record(&self, rect: &mxcfb_rect, waveform: waveform_mode, full_update: bool) {
+        let mut tiles = self.tiles.lock().unwrap();
+        if !tiles.config.enabled {
+            return;
+        }
+        let fast = is_fast(waveform);
+        if !fast && !(full_update && is_cleaning(waveform)) {
+            return;
+        }
+        let (first_col, last_col, first_row, last_row) = match tiles.touched(rect) {
+            Some(span) => span,
+            None => return,
+        };
+        let cols = tiles.cols;
+        for row in first_row..last_row + 1 {
+            for col in first_col..last_col + 1 {
+                let index = (row * cols + col) as usize;
+                if fast {
+                    tiles.counts[index] = tiles.counts[index].saturating_add(1);
+                } else if tiles.covers(rect, col, row) {
+                    tiles.counts[index] = 0;
+                }
+            }
+        }
+        if fast {
+            tiles.last_fast_update = Some(Instant::now());
+        }
+    }
+
+    /// Forgets the updates counted so far, e.g. after the whole screen has been cleaned up
+    pub fn reset(&self) {
+        let mut tiles = self.tiles.lock().unwrap();
+        tiles.counts.iter_mut().for_each(|c| *c = 0);
+        tiles.last_fast_update = None;
+    }
+
+    /// Holds off any cleanup until `end_stroke()` is called
+    pub fn begin_stroke(&self) {
+        self.stroke_active.store(true, Ordering::Relaxed);
+    }
+
+    /// Allows the cleanup to proceed again. Restarts the idle timeout so that the tiles
+    /// touched by the stroke aren't cleaned up right away.
+    pub fn end_stroke(&self) {
+        if self.stroke_active.swap(false, Ordering::Relaxed) {
+            let mut tiles = self.tiles.lock().unwrap();
+            if tiles.last_fast_update.is_some() {
+                tiles.last_fast_update = Some(Instant::now());
+            }
+        }
+    }
+
+    pub fn stroke_active(&self) -> bool {
+        self.stroke_active.load(Ordering::Relaxed)
+    }
+
+    /// Returns the regions that are due for a cleanup, merged into as few rects as possible,
+    /// and resets their counts. Returns nothing while a stroke is in progress.
+    pub fn take_due(&self) -> Vec<mxcfb_rect> {
+        if self.stroke_active() {
+            return Vec::new();
+        }
+        let mut tiles = self.tiles.lock().unwrap();
+        if !tiles.config.enabled || tiles.last_fast_update.is_none() {
+            return Vec::new();
+        }
+        let idle = match (tiles.config.idle_timeout, tiles.last_fast_update) {
+            (Some(timeout), Some(last)) => last.elapsed() >= timeout,
+            _ => false,
+        };
+        let threshold = ::std::cmp::max(tiles.config.fast_update_threshold, 1);
+        let due: Vec<bool> = tiles
+            .counts
+            .iter()
+            .map(|&c| c >= threshold || (idle && c > 0))
+            .collect();
+        if !due.iter().any(|&d| d) {
+            return Vec::new();
+        }
+
+        for (count, &due) in tiles.counts.iter_mut().zip(due.iter()) {
+            if due {
+                *count = 0;
+            }
+        }
+        if tiles.counts.iter().all(|&c| c == 0) {
+            tiles.last_fast_update = None;
+        }
+        dirty_blocks_to_rects(
+            &due,
+            tiles.cols,
+            tiles.tile_size,
+            cgmath::Point2 { x: 0, y: 0 },
+            self.bounds,
+        )
+    }
+}
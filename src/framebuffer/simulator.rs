@@ -0,0 +1,488 @@
+use image;
+use libc;
+use mmap;
+use mmap::MemoryMap;
+
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use framebuffer::common::{
+    mxcfb_rect, update_mode, waveform_mode, NativeWidthType, EPDC_FLAG_ENABLE_INVERSION,
+    EPDC_FLAG_FORCE_MONOCHROME, EPDC_FLAG_TEST_COLLISION, FBIOGET_FSCREENINFO, FBIOGET_VSCREENINFO,
+    FBIOPAN_DISPLAY, MXCFB_SEND_UPDATE, MXCFB_WAIT_FOR_UPDATE_COMPLETE,
+};
+use framebuffer::device::{as_bytes, from_bytes, FramebufferDevice, MockDevice};
+use framebuffer::io::native_to_gray8;
+use framebuffer::mxcfb::{mxcfb_update_data, mxcfb_update_marker_data};
+use framebuffer::screeninfo::{FixScreeninfo, VarScreeninfo};
+
+/// Updates that are never waited for are forgotten after this many more updates
+const MAX_COMPLETIONS: usize = 1024;
+
+/// How a waveform drives the pixels it updates
+struct WaveformBehaviour {
+    /// Number of gray levels it can reach
+    levels: u32,
+    /// Share of the previous gray level that lingers on as ghosting after a partial
+    /// update, in thousandths
+    residue: u32,
+    /// Roughly how long the waveform takes at room temperature
+    duration_ms: u64,
+}
+
+// The waveform modes as found in `mxcfb_update_data`
+const INIT: u32 = waveform_mode::WAVEFORM_MODE_INIT as u32;
+const DU: u32 = waveform_mode::WAVEFORM_MODE_DU as u32;
+const GC16: u32 = waveform_mode::WAVEFORM_MODE_GC16 as u32;
+const GLR16: u32 = waveform_mode::WAVEFORM_MODE_GLR16 as u32;
+const DU4: u32 = waveform_mode::WAVEFORM_MODE_DU4 as u32;
+const GL4: u32 = waveform_mode::WAVEFORM_MODE_GL4 as u32;
+const REAGL: u32 = waveform_mode::WAVEFORM_MODE_REAGL as u32;
+const REAGLD: u32 = waveform_mode::WAVEFORM_MODE_REAGLD as u32;
+const AUTO: u32 = waveform_mode::WAVEFORM_MODE_AUTO as u32;
+
+fn waveform_behaviour(waveform_mode: u32) -> WaveformBehaviour {
+    let (levels, residue, duration_ms) = match waveform_mode {
+        // DU and the A2-like GLR16 only reach black and white, leaving the most behind
+        DU => (2, 120, 260),
+        GLR16 => (2, 150, 120),
+        DU4 | GL4 => (4, 80, 290),
+        GC16 => (16, 20, 760),
+        // The ghost compensating waveforms
+        REAGL | REAGLD => (16, 10, 640),
+        // GC16_FAST, GLD16, GL16_FAST, GL16_INV
+        _ => (16, 50, 450),
+    };
+    WaveformBehaviour {
+        levels,
+        residue,
+        duration_ms,
+    }
+}
+
+/// Rounds `v` to the closest of `levels` evenly spaced gray levels
+fn quantize(v: u8, levels: u32) -> u8 {
+    let steps = levels - 1;
+    let step = (u32::from(v) * steps + 127) / 255;
+    (step * 255 / steps) as u8
+}
+
+fn overlaps(a: &mxcfb_rect, b: &mxcfb_rect) -> bool {
+    a.left < b.left + b.width
+        && b.left < a.left + a.width
+        && a.top < b.top + b.height
+        && b.top < a.top + a.height
+}
+
+/// Memory standing in for the framebuffer memory of the driver. It lives in an unlinked
+/// temporary file, which the framebuffer maps separately, so that the simulator keeps
+/// reading its own mapping after the framebuffer unmapped its one.
+struct SharedMemory {
+    file: File,
+    map: MemoryMap,
+}
+
+// The mapping is only read, through the `Mutex` of the `SimulatorDevice`
+unsafe impl Send for SharedMemory {}
+unsafe impl Sync for SharedMemory {}
+
+impl SharedMemory {
+    fn new(len: usize) -> io::Result<SharedMemory> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = ::std::env::temp_dir().join(format!(
+            "libremarkable-simulator-{}-{}",
+            ::std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        fs::remove_file(&path)?;
+        file.set_len(len as u64)?;
+        let map = map_shared(&file, len)?;
+        Ok(SharedMemory { file, map })
+    }
+}
+
+/// Maps the first `len` bytes of `file`, shared with every other mapping of it
+fn map_shared(file: &File, len: usize) -> io::Result<MemoryMap> {
+    MemoryMap::new(
+        len,
+        &[
+            mmap::MapOption::MapReadable,
+            mmap::MapOption::MapWritable,
+            mmap::MapOption::MapFd(file.as_raw_fd()),
+            mmap::MapOption::MapOffset(0),
+            mmap::MapOption::MapNonStandardFlags(libc::MAP_SHARED),
+        ],
+    ).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+}
+
+struct SimulatedUpdate {
+    marker: u32,
+    region: mxcfb_rect,
+    /// Simulated time at which the waveform is over, see `Panel::clock`
+    done_at: Duration,
+}
+
+struct Panel {
+    width: u32,
+    height: u32,
+    /// The gray levels the EPDC last drove the pixels towards
+    working: Vec<u8>,
+    /// The gray levels the pixels actually show, ghosting included
+    shown: Vec<u8>,
+    output: Option<PathBuf>,
+    frames: u32,
+    in_flight: Vec<SimulatedUpdate>,
+    /// The completion time and collision of the updates that haven't been waited for
+    completions: HashMap<u32, (Duration, bool)>,
+    completion_order: Vec<u32>,
+    /// When the simulation started, the origin of the simulated time in realtime mode
+    started: Instant,
+    /// The simulated time otherwise, which moves forward to the end of every update that
+    /// is waited for rather than with the wall clock
+    clock: Duration,
+}
+
+impl Panel {
+    fn now(&self, realtime: bool) -> Duration {
+        if realtime {
+            self.started.elapsed()
+        } else {
+            self.clock
+        }
+    }
+
+    fn image(&self) -> image::GrayImage {
+        image::GrayImage::from_raw(self.width, self.height, self.shown.clone()).unwrap()
+    }
+
+    /// Writes the current state of the panel as the next frame of the output, if any
+    fn emit_frame(&mut self) {
+        let path = match self.output {
+            Some(ref dir) => dir.join(format!("frame_{:05}.png", self.frames)),
+            None => return,
+        };
+        self.frames += 1;
+        let result = fs::File::create(&path).and_then(|file| {
+            image::png::PNGEncoder::new(file).encode(
+                &self.shown,
+                self.width,
+                self.height,
+                image::ColorType::Gray(8),
+            )
+        });
+        if let Err(e) = result {
+            warn!("Failed to write {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// A handle on the panel of a `SimulatorDevice`, which stays usable once the device has
+/// been handed over to the framebuffer
+#[derive(Clone)]
+pub struct SimulatedPanel {
+    panel: Arc<Mutex<Panel>>,
+}
+
+impl SimulatedPanel {
+    /// What the panel shows at this point, ghosting included
+    pub fn image(&self) -> image::GrayImage {
+        self.panel.lock().unwrap().image()
+    }
+
+    pub fn save_png(&self, path: &str) -> io::Result<()> {
+        let panel = self.panel.lock().unwrap();
+        image::png::PNGEncoder::new(fs::File::create(path)?).encode(
+            &panel.shown,
+            panel.width,
+            panel.height,
+            image::ColorType::Gray(8),
+        )
+    }
+
+    /// Number of frames written to the output so far
+    pub fn frame_count(&self) -> u32 {
+        self.panel.lock().unwrap().frames
+    }
+}
+
+/// A `MockDevice` that also simulates the e-ink panel the updates are sent to, so that the
+/// way they look can be checked without a reMarkable.
+///
+/// The simulated panel is kept apart from the framebuffer memory and only changes when an
+/// update is sent, following the semantics of its waveform: DU only drives the pixels
+/// heading to black or white and leaves the others as they are, GLR16 only reaches black
+/// and white, GC16 and the fast grayscale waveforms 16 gray levels, and INIT flashes the
+/// region to white. Partial updates only drive the pixels that changed and leave some of
+/// their previous gray level behind as ghosting, which builds up until a full update
+/// flashes it away. `ENABLE_INVERSION` and `FORCE_MONOCHROME` are honored, while alternate
+/// buffers and colormaps aren't simulated.
+///
+/// Updates take the time their waveform takes, and overlapping an update still in flight
+/// is reported as a collision once it is waited for. Unless running in realtime, the time
+/// is simulated: it stands still between updates and moves to the end of every update that
+/// is waited for, so updates sent back to back overlap while waiting for one completes it
+/// along with every other update that is over by then. With an output directory set, every
+/// update and flash is written there as a numbered PNG frame.
+pub struct SimulatorDevice {
+    inner: MockDevice,
+    panel: Arc<Mutex<Panel>>,
+    /// The framebuffer memory handed out by `map(..)`
+    memory: Mutex<Option<SharedMemory>>,
+    yoffset: Mutex<u32>,
+    realtime: bool,
+}
+
+impl SimulatorDevice {
+    pub fn new() -> SimulatorDevice {
+        let inner = MockDevice::new();
+        let mut var: VarScreeninfo = Default::default();
+        let _ = unsafe { inner.ioctl(FBIOGET_VSCREENINFO, as_bytes(&mut var)) };
+        let pixels = (var.xres * var.yres) as usize;
+        let panel = Panel {
+            width: var.xres,
+            height: var.yres,
+            working: vec![255; pixels],
+            shown: vec![255; pixels],
+            output: None,
+            frames: 0,
+            in_flight: Vec::new(),
+            completions: HashMap::new(),
+            completion_order: Vec::new(),
+            started: Instant::now(),
+            clock: Duration::from_millis(0),
+        };
+        SimulatorDevice {
+            inner,
+            panel: Arc::new(Mutex::new(panel)),
+            memory: Mutex::new(None),
+            yoffset: Mutex::new(0),
+            realtime: false,
+        }
+    }
+
+    /// Writes the frames into `dir` as `frame_00000.png` and so on, creating it if needed
+    pub fn with_output(dir: &str) -> io::Result<SimulatorDevice> {
+        fs::create_dir_all(dir)?;
+        let device = SimulatorDevice::new();
+        device.panel.lock().unwrap().output = Some(PathBuf::from(dir));
+        Ok(device)
+    }
+
+    /// Whether waiting for an update blocks until the time its waveform takes has passed,
+    /// as it does on the device. Off by default, so that waiting returns right away and
+    /// only moves the simulated time forward.
+    pub fn realtime(mut self, realtime: bool) -> SimulatorDevice {
+        self.realtime = realtime;
+        self
+    }
+
+    pub fn panel(&self) -> SimulatedPanel {
+        SimulatedPanel {
+            panel: self.panel.clone(),
+        }
+    }
+
+    /// Reads the gray levels the update drives the pixels of `region` towards
+    fn read_region(&self, region: &mxcfb_rect, flags: u32) -> Option<Vec<u8>> {
+        let memory = self.memory.lock().unwrap();
+        let map = &memory.as_ref()?.map;
+        let (address, len) = (map.data(), map.len());
+        let mut fix: FixScreeninfo = Default::default();
+        let _ = unsafe { self.inner.ioctl(FBIOGET_FSCREENINFO, as_bytes(&mut fix)) };
+        let line_length = fix.line_length as usize;
+        let page_start = *self.yoffset.lock().unwrap() as usize * line_length;
+        let begin = address as *const u8;
+        let mut gray = Vec::with_capacity((region.width * region.height) as usize);
+        for y in region.top..region.top + region.height {
+            for x in region.left..region.left + region.width {
+                let index = page_start + y as usize * line_length + x as usize * 2;
+                if index + 1 >= len {
+                    return None;
+                }
+                let (c1, c2) = unsafe {
+                    (
+                        begin.offset(index as isize).read_volatile(),
+                        begin.offset(index as isize + 1).read_volatile(),
+                    )
+                };
+                let mut v = native_to_gray8(c1, c2);
+                if flags & EPDC_FLAG_ENABLE_INVERSION != 0 {
+                    v = 255 - v;
+                }
+                if flags & EPDC_FLAG_FORCE_MONOCHROME != 0 {
+                    v = quantize(v, 2);
+                }
+                gray.push(v);
+            }
+        }
+        Some(gray)
+    }
+
+    fn send_update(&self, update: &mxcfb_update_data) {
+        let mut panel = self.panel.lock().unwrap();
+        let mut region = update.update_region;
+        region.width = ::std::cmp::min(region.width, panel.width.saturating_sub(region.left));
+        region.height = ::std::cmp::min(region.height, panel.height.saturating_sub(region.top));
+
+        let now = panel.now(self.realtime);
+        panel.in_flight.retain(|u| u.done_at > now);
+        let collision = panel.in_flight.iter().any(|u| overlaps(&u.region, &region));
+        let mut waveform_mode = update.waveform_mode;
+        let targets = self.read_region(&region, update.flags);
+        if waveform_mode == AUTO {
+            // The driver picks DU when the region only holds black and white
+            let monochrome = targets
+                .as_ref()
+                .map_or(false, |t| t.iter().all(|&v| v == 0 || v == 255));
+            waveform_mode = if monochrome { DU } else { GC16 };
+        }
+        let behaviour = waveform_behaviour(waveform_mode);
+        let test_collision = update.flags & EPDC_FLAG_TEST_COLLISION != 0;
+        // A collision test doesn't run a waveform, it is done as soon as it is checked
+        let done_at = if test_collision {
+            now
+        } else {
+            now + Duration::from_millis(behaviour.duration_ms)
+        };
+
+        let marker = update.update_marker;
+        if panel.completion_order.len() >= MAX_COMPLETIONS {
+            let oldest = panel.completion_order.remove(0);
+            panel.completions.remove(&oldest);
+        }
+        panel.completion_order.push(marker);
+        panel.completions.insert(marker, (done_at, collision));
+
+        if test_collision || region.width == 0 || region.height == 0 {
+            return;
+        }
+        panel.in_flight.push(SimulatedUpdate {
+            marker,
+            region,
+            done_at,
+        });
+
+        let targets = match targets {
+            Some(targets) => targets,
+            None => {
+                warn!("The framebuffer memory isn't mapped, not simulating the update");
+                return;
+            }
+        };
+        let full =
+            waveform_mode == INIT || update.update_mode == update_mode::UPDATE_MODE_FULL as u32;
+        let width = panel.width as usize;
+        let mut indices = Vec::with_capacity(targets.len());
+        for y in region.top..region.top + region.height {
+            for x in region.left..region.left + region.width {
+                indices.push(y as usize * width + x as usize);
+            }
+        }
+
+        if full {
+            // The flash drives the whole region through black first
+            for &i in &indices {
+                panel.shown[i] = 0;
+            }
+            panel.emit_frame();
+        }
+        for (n, &i) in indices.iter().enumerate() {
+            // DU has no transitions towards the gray levels, those pixels keep what they show
+            if waveform_mode == DU && targets[n] != 0 && targets[n] != 255 {
+                continue;
+            }
+            let target = if waveform_mode == INIT {
+                255
+            } else {
+                quantize(targets[n], behaviour.levels)
+            };
+            if full {
+                panel.shown[i] = target;
+            } else if target != panel.working[i] {
+                let previous = i64::from(panel.shown[i]);
+                let ghost = (previous - i64::from(target)) * i64::from(behaviour.residue) / 1000;
+                panel.shown[i] = (i64::from(target) + ghost) as u8;
+            } else {
+                continue;
+            }
+            panel.working[i] = target;
+        }
+        panel.emit_frame();
+    }
+
+    fn wait_for_update(&self, markerdata: &mut mxcfb_update_marker_data) {
+        let completion = {
+            let mut panel = self.panel.lock().unwrap();
+            let marker = markerdata.update_marker;
+            let completion = panel.completions.remove(&marker);
+            panel.completion_order.retain(|&m| m != marker);
+            // Once waited for, the update is over even if the simulation doesn't sleep
+            panel.in_flight.retain(|u| u.marker != marker);
+            completion
+        };
+        if let Some((done_at, collision)) = completion {
+            if self.realtime {
+                let now = self.panel.lock().unwrap().now(true);
+                if done_at > now {
+                    thread::sleep(done_at - now);
+                }
+            } else {
+                let mut panel = self.panel.lock().unwrap();
+                panel.clock = ::std::cmp::max(panel.clock, done_at);
+            }
+            markerdata.collision_test = collision as u32;
+        }
+    }
+}
+
+impl Default for SimulatorDevice {
+    fn default() -> SimulatorDevice {
+        SimulatorDevice::new()
+    }
+}
+
+impl FramebufferDevice for SimulatorDevice {
+    unsafe fn ioctl(&self, request: NativeWidthType, arg: &mut [u8]) -> io::Result<()> {
+        self.inner.ioctl(request, arg)?;
+        match request {
+            MXCFB_SEND_UPDATE if arg.len() == ::std::mem::size_of::<mxcfb_update_data>() => {
+                let update: mxcfb_update_data = from_bytes(arg);
+                self.send_update(&update);
+            }
+            MXCFB_WAIT_FOR_UPDATE_COMPLETE
+                if arg.len() == ::std::mem::size_of::<mxcfb_update_marker_data>() =>
+            {
+                let mut markerdata: mxcfb_update_marker_data = from_bytes(arg);
+                self.wait_for_update(&mut markerdata);
+                arg.copy_from_slice(as_bytes(&mut markerdata));
+            }
+            FBIOPAN_DISPLAY if arg.len() == ::std::mem::size_of::<VarScreeninfo>() => {
+                let var: VarScreeninfo = from_bytes(arg);
+                *self.yoffset.lock().unwrap() = var.yoffset;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn map(&self, len: usize) -> io::Result<MemoryMap> {
+        let mut memory = self.memory.lock().unwrap();
+        if memory.as_ref().map_or(true, |m| m.map.len() < len) {
+            *memory = Some(SharedMemory::new(len)?);
+        }
+        map_shared(&memory.as_ref().unwrap().file, len)
+    }
+}
@@ -0,0 +1,82 @@
+use std::io;
+use std::time::Duration;
+
+use framebuffer::cmap::Colormap;
+use framebuffer::common::{auto_update_mode, update_scheme};
+use framebuffer::core::Framebuffer;
+use framebuffer::FramebufferBase;
+
+/// The global settings of the EPDC driver that outlive the process changing them, as last
+/// set through the framebuffer
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DriverState {
+    pub epdc_access: bool,
+    pub autoupdate_mode: auto_update_mode,
+    pub update_scheme: update_scheme,
+}
+
+impl Default for DriverState {
+    /// The settings the driver starts out with. They can't be read back from the driver,
+    /// so they are assumed to be in effect when the framebuffer is created.
+    fn default() -> DriverState {
+        DriverState {
+            epdc_access: true,
+            autoupdate_mode: auto_update_mode::AUTO_UPDATE_MODE_REGION_MODE,
+            update_scheme: update_scheme::UPDATE_SCHEME_QUEUE_AND_MERGE,
+        }
+    }
+}
+
+/// The global settings that `Framebuffer::restore()` puts back besides the `DriverState`,
+/// as found before they were first changed through the framebuffer
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PinnedSettings {
+    /// Whether the temperature is pinned instead of read from the sensor
+    pub temperature: bool,
+    pub powerdown_delay: Option<Option<Duration>>,
+    pub colormap: Option<Colormap>,
+}
+
+/// Puts back the `DriverState` found when it was created once it goes out of scope.
+/// Returned by the `scoped_*` methods of the framebuffer.
+pub struct DriverStateGuard<'f, 'a: 'f> {
+    fb: &'f Framebuffer<'a>,
+    previous: DriverState,
+}
+
+impl<'f, 'a: 'f> DriverStateGuard<'f, 'a> {
+    pub(crate) fn new(fb: &'f Framebuffer<'a>) -> DriverStateGuard<'f, 'a> {
+        DriverStateGuard {
+            fb,
+            previous: fb.driver_state(),
+        }
+    }
+
+    /// The state that will be restored
+    pub fn previous(&self) -> DriverState {
+        self.previous
+    }
+}
+
+impl<'f, 'a: 'f> Drop for DriverStateGuard<'f, 'a> {
+    fn drop(&mut self) {
+        if let Err(e) = restore(self.fb, &self.previous) {
+            warn!("Failed to restore {:?}: {}", self.previous, e);
+        }
+    }
+}
+
+/// Changes the settings of `fb` that differ from `state`
+pub(crate) fn restore(fb: &Framebuffer, state: &DriverState) -> io::Result<()> {
+    let current = fb.driver_state();
+    if current.update_scheme != state.update_scheme {
+        fb.set_update_scheme(state.update_scheme)?;
+    }
+    if current.autoupdate_mode != state.autoupdate_mode {
+        fb.set_autoupdate_mode(state.autoupdate_mode)?;
+    }
+    if current.epdc_access != state.epdc_access {
+        fb.set_epdc_access(state.epdc_access)?;
+    }
+    Ok(())
+}
@@ -1,6 +1,61 @@
+use std::io;
+use std::io::{Read, Write};
 use std::sync::Arc;
 use zstd;
 
+/// Identifies a `CompressedCanvasState` written with `write_to(..)`
+const CANVAS_STATE_MAGIC: [u8; 4] = *b"LRCS";
+
+/// Bumped whenever the layout of the header or the payload changes
+const CANVAS_STATE_VERSION: u16 = 1;
+
+/// magic (4) + version (2) + pixel format (2) + width (4) + height (4)
+///   + payload length (4) + payload checksum (4)
+const CANVAS_STATE_HEADER_LEN: usize = 24;
+
+/// The pixel formats a `CompressedCanvasState` can hold. `dump_region(..)` only
+/// produces `RGB565_LE` for now.
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PixelFormat {
+    RGB565_LE = 1,
+}
+
+impl PixelFormat {
+    fn from_u16(v: u16) -> Option<PixelFormat> {
+        match v {
+            1 => Some(PixelFormat::RGB565_LE),
+            _ => None,
+        }
+    }
+
+    pub fn bytes_per_pixel(self) -> u32 {
+        match self {
+            PixelFormat::RGB565_LE => 2,
+        }
+    }
+}
+
+/// Adler-32 of `data`, used to detect truncated or corrupted canvas state files
+fn adler32(data: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552 is the largest n such that the sums can't overflow before the modulo
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += u32::from(byte);
+            b += a;
+        }
+        a %= MOD_ADLER;
+        b %= MOD_ADLER;
+    }
+    (b << 16) | a
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
 #[derive(Clone)]
 pub struct CompressedCanvasState {
     data: Arc<[u8]>,
@@ -33,9 +88,120 @@ impl CompressedCanvasState {
     }
 
     /// Returns an ImageBuffer which can be used to restore the contents of a screen
-    /// region using the FramebufferIO::restore_region(..)
+    /// region using the FramebufferIO::restore_region(..). Panics if the payload is corrupt,
+    /// see `try_decompress()` for states read from untrusted storage.
     pub fn decompress(&self) -> Vec<u8> {
-        zstd::decode_all(&*self.data).unwrap()
+        self.try_decompress().unwrap()
+    }
+
+    /// Like `decompress()`, but fails with `InvalidData` if the payload is corrupt or
+    /// doesn't decompress to exactly `width * height` pixels.
+    pub fn try_decompress(&self) -> io::Result<Vec<u8>> {
+        let data = zstd::decode_all(&*self.data)?;
+        let expected = self.width as usize
+            * self.height as usize
+            * self.pixel_format().bytes_per_pixel() as usize;
+        if data.len() != expected {
+            return Err(invalid_data(format!(
+                "CompressedCanvasState decompressed to {0} bytes instead of {1}",
+                data.len(),
+                expected
+            )));
+        }
+        Ok(data)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn pixel_format(&self) -> PixelFormat {
+        PixelFormat::RGB565_LE
+    }
+
+    /// Writes the state into `writer` as a small little-endian header followed by the
+    /// zstd payload. The header carries a magic number, the format version, the pixel
+    /// format, the dimensions, and the length and Adler-32 checksum of the payload.
+    pub fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        let mut header = Vec::with_capacity(CANVAS_STATE_HEADER_LEN);
+        header.extend_from_slice(&CANVAS_STATE_MAGIC);
+        header.extend_from_slice(&CANVAS_STATE_VERSION.to_le_bytes());
+        header.extend_from_slice(&(self.pixel_format() as u16).to_le_bytes());
+        header.extend_from_slice(&self.width.to_le_bytes());
+        header.extend_from_slice(&self.height.to_le_bytes());
+        header.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        header.extend_from_slice(&adler32(&self.data).to_le_bytes());
+        writer.write_all(&header)?;
+        writer.write_all(&self.data)
+    }
+
+    /// Reads a state previously written with `write_to(..)`. Files with a different magic
+    /// number, an unknown version or pixel format, or a payload that doesn't match the
+    /// recorded length and checksum are rejected with an `InvalidData` error.
+    pub fn read_from(reader: &mut dyn Read) -> io::Result<CompressedCanvasState> {
+        let mut header = [0u8; CANVAS_STATE_HEADER_LEN];
+        reader.read_exact(&mut header)?;
+
+        let u16_at = |i: usize| u16::from(header[i]) | u16::from(header[i + 1]) << 8;
+        let u32_at = |i: usize| {
+            u32::from(header[i])
+                | u32::from(header[i + 1]) << 8
+                | u32::from(header[i + 2]) << 16
+                | u32::from(header[i + 3]) << 24
+        };
+
+        if header[0..4] != CANVAS_STATE_MAGIC {
+            return Err(invalid_data(
+                "Not a CompressedCanvasState file (bad magic number)".to_owned(),
+            ));
+        }
+        let version = u16_at(4);
+        if version != CANVAS_STATE_VERSION {
+            return Err(invalid_data(format!(
+                "Unsupported CompressedCanvasState version {0} (expected {1})",
+                version, CANVAS_STATE_VERSION
+            )));
+        }
+        let format = u16_at(6);
+        if PixelFormat::from_u16(format).is_none() {
+            return Err(invalid_data(format!(
+                "Unsupported pixel format {0} in CompressedCanvasState",
+                format
+            )));
+        }
+        let (width, height) = (u32_at(8), u32_at(12));
+        if width == 0 || height == 0 {
+            return Err(invalid_data(format!(
+                "Invalid CompressedCanvasState dimensions {0}x{1}",
+                width, height
+            )));
+        }
+        let (len, checksum) = (u32_at(16), u32_at(20));
+
+        let mut data = Vec::new();
+        reader.take(u64::from(len)).read_to_end(&mut data)?;
+        if data.len() != len as usize {
+            return Err(invalid_data(format!(
+                "Truncated CompressedCanvasState payload ({0} of {1} bytes)",
+                data.len(),
+                len
+            )));
+        }
+        if adler32(&data) != checksum {
+            return Err(invalid_data(
+                "CompressedCanvasState payload checksum mismatch".to_owned(),
+            ));
+        }
+
+        Ok(CompressedCanvasState {
+            data: data.into(),
+            height,
+            width,
+        })
     }
 }
 
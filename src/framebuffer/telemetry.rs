@@ -0,0 +1,285 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use framebuffer::common::{mxcfb_rect, waveform_mode};
+
+/// Updates whose completion is never waited for are forgotten after this many more updates
+const MAX_PENDING: usize = 1024;
+/// Values up to 2^(BUCKETS - 1) land in their own bucket, larger ones in the last one
+const BUCKETS: usize = 32;
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros())
+}
+
+/// Counts values into power of two buckets: bucket `i` holds the values in `[2^(i-1), 2^i)`,
+/// with bucket 0 holding zero.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: [0; BUCKETS],
+            count: 0,
+            sum: 0,
+            min: u64::max_value(),
+            max: 0,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, value: u64) {
+        let bucket = (64 - value.leading_zeros()) as usize;
+        self.buckets[::std::cmp::min(bucket, BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+        self.min = ::std::cmp::min(self.min, value);
+        self.max = ::std::cmp::max(self.max, value);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn min(&self) -> Option<u64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.min)
+        }
+    }
+
+    pub fn max(&self) -> Option<u64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.max)
+        }
+    }
+
+    pub fn mean(&self) -> Option<u64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.sum / self.count)
+        }
+    }
+
+    /// Returns an upper bound of the value below which `percent` of the recorded values fall,
+    /// precise to the power of two bucket it lies in
+    pub fn percentile(&self, percent: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((percent / 100.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let upper = if bucket == 0 { 0 } else { (1u64 << bucket) - 1 };
+                return Some(::std::cmp::min(upper, self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    /// The non-empty buckets as `(lowest value, highest value, count)`
+    pub fn buckets(&self) -> Vec<(u64, u64, u64)> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(bucket, &count)| {
+                if bucket == 0 {
+                    (0, 0, count)
+                } else {
+                    (1u64 << (bucket - 1), (1u64 << bucket) - 1, count)
+                }
+            })
+            .collect()
+    }
+}
+
+/// The metrics aggregated for all the updates performed with a waveform
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WaveformStats {
+    /// Time spent in the `MXCFB_SEND_UPDATE` ioctl, in microseconds
+    pub submit_us: Histogram,
+    /// Time from the submission of an update to its completion, in microseconds. Only
+    /// covers the updates whose completion has been waited for.
+    pub completion_us: Histogram,
+    /// Number of pixels refreshed per update
+    pub area: Histogram,
+    /// Number of completed updates that reported a collision
+    pub collisions: u64,
+}
+
+struct PendingUpdate {
+    marker: u32,
+    waveform: waveform_mode,
+    submitted_at: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    pending: VecDeque<PendingUpdate>,
+    stats: HashMap<waveform_mode, WaveformStats>,
+}
+
+/// Records the latency and size of every update going through the framebuffer, aggregated
+/// per waveform into histograms that can be queried through `snapshot()` or written out
+/// with `dump(..)`.
+///
+/// The completion of an update is measured when it is waited for, be it by the refresh
+/// itself, `wait_refresh_complete(..)` or the `CompletionService`.
+///
+/// It is disabled by default so that refreshes don't pay for the bookkeeping, and can be
+/// turned on with `fb.telemetry.set_enabled(true)`.
+pub struct Telemetry {
+    enabled: AtomicBool,
+    state: Mutex<State>,
+}
+
+impl Telemetry {
+    pub fn new(enabled: bool) -> Telemetry {
+        Telemetry {
+            enabled: AtomicBool::new(enabled),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Records the submission of the update tagged with `marker`, which started at
+    /// `submitted_at` and took `submit_time`
+    pub fn record_submit(
+        &self,
+        marker: u32,
+        region: &mxcfb_rect,
+        waveform: waveform_mode,
+        submitted_at: Instant,
+        submit_time: Duration,
+    ) {
+        if !self.enabled() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        {
+            let stats = state.stats.entry(waveform).or_insert_with(Default::default);
+            stats.submit_us.record(micros(submit_time));
+            stats
+                .area
+                .record(u64::from(region.width) * u64::from(region.height));
+        }
+        if state.pending.len() >= MAX_PENDING {
+            state.pending.pop_front();
+        }
+        state.pending.push_back(PendingUpdate {
+            marker,
+            waveform,
+            submitted_at,
+        });
+    }
+
+    /// Records the completion of the update tagged with `marker`. `collision_test` is `None`
+    /// if waiting for it failed, in which case only its pending entry gets dropped.
+    pub fn record_completion(&self, marker: u32, collision_test: Option<u32>) {
+        if !self.enabled() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let index = match state.pending.iter().position(|p| p.marker == marker) {
+            Some(index) => index,
+            None => return,
+        };
+        let update = state.pending.remove(index).unwrap();
+        let collision_test = match collision_test {
+            Some(collision_test) => collision_test,
+            None => return,
+        };
+        let stats = state
+            .stats
+            .entry(update.waveform)
+            .or_insert_with(Default::default);
+        stats
+            .completion_us
+            .record(micros(update.submitted_at.elapsed()));
+        if collision_test != 0 {
+            stats.collisions += 1;
+        }
+    }
+
+    /// Returns a copy of the metrics aggregated so far
+    pub fn snapshot(&self) -> HashMap<waveform_mode, WaveformStats> {
+        self.state.lock().unwrap().stats.clone()
+    }
+
+    /// Forgets all the metrics, e.g. before starting the workload to measure
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.pending.clear();
+        state.stats.clear();
+    }
+
+    /// Writes a human readable summary of the metrics into `writer`
+    pub fn dump(&self, writer: &mut dyn io::Write) -> io::Result<()> {
+        let mut stats: Vec<(waveform_mode, WaveformStats)> = self.snapshot().into_iter().collect();
+        stats.sort_by_key(|&(waveform, _)| waveform as u32);
+        for (waveform, stats) in stats {
+            writeln!(
+                writer,
+                "{:?}: {} updates, {} completed, {} collisions",
+                waveform,
+                stats.submit_us.count(),
+                stats.completion_us.count(),
+                stats.collisions
+            )?;
+            for &(name, ref histogram) in &[
+                ("submit (us)", &stats.submit_us),
+                ("completion (us)", &stats.completion_us),
+                ("area (px)", &stats.area),
+            ] {
+                if histogram.count() == 0 {
+                    continue;
+                }
+                writeln!(
+                    writer,
+                    "  {:<16} min {} mean {} p50 {} p99 {} max {}",
+                    name,
+                    histogram.min().unwrap_or(0),
+                    histogram.mean().unwrap_or(0),
+                    histogram.percentile(50.0).unwrap_or(0),
+                    histogram.percentile(99.0).unwrap_or(0),
+                    histogram.max().unwrap_or(0)
+                )?;
+                for (low, high, count) in histogram.buckets() {
+                    writeln!(writer, "    {:>10} - {:<10} {}", low, high, count)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Telemetry {
+    fn default() -> Telemetry {
+        Telemetry::new(false)
+    }
+}
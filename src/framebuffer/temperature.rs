@@ -0,0 +1,131 @@
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use battery;
+use framebuffer::common::{display_temp, waveform_mode};
+
+/// Drivers of the EPD power management ICs that expose the panel temperature through hwmon
+const PANEL_SENSOR_NAMES: [&str; 4] = ["max17135", "tps65185", "sy7636a_temperature", "fp9928"];
+
+/// Below this many degrees Celsius the fast grayscale waveforms leave visible ghosting behind
+pub const COLD_THRESHOLD: i32 = 10;
+
+/// The temperature the EPDC looks up the timing of a waveform for
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TemperatureMode {
+    /// One of the values the driver handles specially
+    Fixed(display_temp),
+    /// An explicit temperature in degrees Celsius
+    Celsius(i32),
+    /// The temperature measured by the `TemperatureSensor` of the framebuffer, which also
+    /// gets to adjust the waveform to it. See `TemperatureSensor::resolve(..)`.
+    Auto,
+}
+
+impl From<display_temp> for TemperatureMode {
+    fn from(temp: display_temp) -> TemperatureMode {
+        TemperatureMode::Fixed(temp)
+    }
+}
+
+/// Reads the temperature of the panel, or failing that the one of the battery, which sits
+/// right behind it. Readings are cached for `max_age` as the temperature changes slowly
+/// and reading sysfs on every refresh would add to the latency of drawing.
+pub struct TemperatureSensor {
+    max_age: Duration,
+    cached: Mutex<Option<(Instant, Option<i32>)>>,
+}
+
+impl TemperatureSensor {
+    pub fn new(max_age: Duration) -> TemperatureSensor {
+        TemperatureSensor {
+            max_age,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Returns the temperature in degrees Celsius, `None` if no sensor could be read
+    pub fn celsius(&self) -> Option<i32> {
+        let mut cached = self.cached.lock().unwrap();
+        if let Some((read_at, celsius)) = *cached {
+            if read_at.elapsed() < self.max_age {
+                return celsius;
+            }
+        }
+        let celsius = read_panel_temperature().or_else(read_battery_temperature);
+        *cached = Some((Instant::now(), celsius));
+        celsius
+    }
+
+    /// Forgets the cached reading so that the next one goes to the sensor
+    pub fn invalidate(&self) {
+        *self.cached.lock().unwrap() = None;
+    }
+
+    /// Returns the raw temperature value to send to the EPDC for `mode`, along with the
+    /// waveform to use in place of `waveform`.
+    ///
+    /// With `TemperatureMode::Auto`, the measured temperature is sent and, below
+    /// `COLD_THRESHOLD`, the fast grayscale waveforms get replaced by GC16. If nothing could
+    /// be measured, the driver is left to read its own sensor.
+    pub fn resolve(&self, mode: TemperatureMode, waveform: waveform_mode) -> (i32, waveform_mode) {
+        match mode {
+            TemperatureMode::Fixed(temp) => (temp as i32, waveform),
+            TemperatureMode::Celsius(celsius) => (celsius, waveform),
+            TemperatureMode::Auto => match self.celsius() {
+                Some(celsius) => (celsius, waveform_for_temperature(celsius, waveform)),
+                None => (display_temp::TEMP_USE_AMBIENT as i32, waveform),
+            },
+        }
+    }
+}
+
+impl Default for TemperatureSensor {
+    fn default() -> TemperatureSensor {
+        TemperatureSensor::new(Duration::from_secs(60))
+    }
+}
+
+/// Returns the waveform suited to `waveform` at `celsius` degrees
+pub fn waveform_for_temperature(celsius: i32, waveform: waveform_mode) -> waveform_mode {
+    if celsius >= COLD_THRESHOLD {
+        return waveform;
+    }
+    match waveform {
+        waveform_mode::WAVEFORM_MODE_GC16_FAST | waveform_mode::WAVEFORM_MODE_GL16_FAST => {
+            waveform_mode::WAVEFORM_MODE_GC16
+        }
+        other => other,
+    }
+}
+
+/// hwmon reports temperatures in millidegrees Celsius
+fn read_panel_temperature() -> Option<i32> {
+    for entry in fs::read_dir("/sys/class/hwmon").ok()? {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(_) => continue,
+        };
+        let name = match fs::read_to_string(path.join("name")) {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if !PANEL_SENSOR_NAMES.contains(&name.trim()) {
+            continue;
+        }
+        if let Ok(value) = fs::read_to_string(path.join("temp1_input")) {
+            if let Ok(millidegrees) = value.trim().parse::<i32>() {
+                return Some(millidegrees / 1000);
+            }
+        }
+    }
+    None
+}
+
+/// The battery reports its temperature in tenths of degrees Celsius
+fn read_battery_temperature() -> Option<i32> {
+    battery::temperature()
+        .ok()
+        .map(|decidegrees| decidegrees / 10)
+}
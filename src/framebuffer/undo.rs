@@ -0,0 +1,289 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use zstd;
+
+use framebuffer::common::mxcfb_rect;
+use framebuffer::FramebufferIO;
+
+/// The compressed XOR of the contents of `rect` before and after an operation.
+/// Applying it to either side yields the other, so the same delta serves both
+/// `undo()` and `redo()`.
+struct RegionDelta {
+    rect: mxcfb_rect,
+    data: Arc<[u8]>,
+}
+
+/// All the regions damaged by a single operation
+struct Operation {
+    deltas: Vec<RegionDelta>,
+    size: usize,
+}
+
+/// The contents of a region as they were before the operation in progress touched it
+struct PendingRegion {
+    rect: mxcfb_rect,
+    before: Vec<u8>,
+}
+
+fn overlaps(a: &mxcfb_rect, b: &mxcfb_rect) -> bool {
+    a.left < b.left + b.width
+        && b.left < a.left + a.width
+        && a.top < b.top + b.height
+        && b.top < a.top + a.height
+}
+
+/// Copies the rgb565 rows of `src` (covering `src_rect`) into `dst` (covering `dst_rect`).
+/// `src_rect` needs to be contained in `dst_rect`.
+fn blit(src: &[u8], src_rect: &mxcfb_rect, dst: &mut [u8], dst_rect: &mxcfb_rect) {
+    let row_len = src_rect.width as usize * 2;
+    for row in 0..src_rect.height as usize {
+        let src_index = row * row_len;
+        let dst_index =
+            ((src_rect.top - dst_rect.top) as usize + row) * dst_rect.width as usize * 2
+                + (src_rect.left - dst_rect.left) as usize * 2;
+        dst[dst_index..dst_index + row_len].copy_from_slice(&src[src_index..src_index + row_len]);
+    }
+}
+
+/// Undo/redo history that only keeps the regions damaged by each operation, stored as
+/// zstd compressed XOR deltas against their prior contents.
+///
+/// An operation is recorded by calling `track(..)` with every region that is about to be
+/// drawn over, drawing, and then calling `commit(..)`. Since the deltas are relative,
+/// every change to the tracked regions needs to go through the stack for `undo()` and
+/// `redo()` to restore the right contents.
+///
+/// Once the compressed deltas exceed `memory_budget` bytes, the oldest operations are
+/// dropped from the history, followed by the earliest undone ones.
+pub struct UndoStack {
+    memory_budget: usize,
+    memory_used: usize,
+    undo: VecDeque<Operation>,
+    redo: VecDeque<Operation>,
+    pending: Vec<PendingRegion>,
+}
+
+impl UndoStack {
+    pub fn new(memory_budget: usize) -> UndoStack {
+        UndoStack {
+            memory_budget,
+            memory_used: 0,
+            undo: VecDeque::new(),
+            redo: VecDeque::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Captures the current contents of `rect` as part of the operation in progress. Needs to
+    /// be called before drawing over `rect`. Regions overlapping one that is already tracked
+    /// are merged with it.
+    pub fn track<F: FramebufferIO>(
+        &mut self,
+        fb: &F,
+        rect: mxcfb_rect,
+    ) -> Result<(), &'static str> {
+        let mut merged = rect;
+        let mut absorbed = Vec::new();
+        // Merging may grow the rect into other tracked regions, so repeat until stable
+        loop {
+            let before = absorbed.len();
+            let mut i = 0;
+            while i < self.pending.len() {
+                if overlaps(&self.pending[i].rect, &merged) {
+                    let region = self.pending.remove(i);
+                    merged = merged.merge_rect(&region.rect);
+                    absorbed.push(region);
+                } else {
+                    i += 1;
+                }
+            }
+            if absorbed.len() == before {
+                break;
+            }
+        }
+
+        let mut contents = match fb.dump_region(merged) {
+            Ok(contents) => contents,
+            Err(e) => {
+                // Put back what we took out so that the operation in progress stays intact
+                self.pending.extend(absorbed);
+                return Err(e);
+            }
+        };
+        // The areas that were already tracked may have been drawn over since,
+        // their prior contents are the ones we captured back then.
+        for region in &absorbed {
+            blit(&region.before, &region.rect, &mut contents, &merged);
+        }
+        self.pending.push(PendingRegion {
+            rect: merged,
+            before: contents,
+        });
+        Ok(())
+    }
+
+    /// Records the operation in progress, computing the deltas of all the tracked regions
+    /// against their current contents. Clears the redo history.
+    pub fn commit<F: FramebufferIO>(&mut self, fb: &F) -> Result<(), &'static str> {
+        let pending = ::std::mem::replace(&mut self.pending, Vec::new());
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let mut deltas = Vec::with_capacity(pending.len());
+        let mut size = 0;
+        for region in pending {
+            let mut after = fb.dump_region(region.rect)?;
+            after
+                .iter_mut()
+                .zip(region.before.iter())
+                .for_each(|(a, b)| *a ^= *b);
+            let data: Arc<[u8]> = match zstd::encode_all(after.as_slice(), 0) {
+                Ok(data) => data.into(),
+                Err(_) => return Err("Failed to compress the undo delta"),
+            };
+            size += data.len();
+            deltas.push(RegionDelta {
+                rect: region.rect,
+                data,
+            });
+        }
+
+        for op in self.redo.drain(..) {
+            self.memory_used -= op.size;
+        }
+        self.memory_used += size;
+        self.undo.push_back(Operation { deltas, size });
+        self.enforce_budget();
+        Ok(())
+    }
+
+    /// Forgets the regions tracked for the operation in progress without recording it
+    pub fn discard(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Reverts the last recorded operation. Returns the rects that need to be refreshed,
+    /// which is empty if there was nothing to undo. On failure the framebuffer and the
+    /// history are left as they were.
+    pub fn undo<F: FramebufferIO>(&mut self, fb: &mut F) -> Result<Vec<mxcfb_rect>, &'static str> {
+        let op = match self.undo.pop_back() {
+            Some(op) => op,
+            None => return Ok(Vec::new()),
+        };
+        match UndoStack::apply(fb, &op) {
+            Ok(rects) => {
+                self.redo.push_back(op);
+                Ok(rects)
+            }
+            Err(e) => {
+                self.undo.push_back(op);
+                Err(e)
+            }
+        }
+    }
+
+    /// Reapplies the last undone operation. Returns the rects that need to be refreshed,
+    /// which is empty if there was nothing to redo. On failure the framebuffer and the
+    /// history are left as they were.
+    pub fn redo<F: FramebufferIO>(&mut self, fb: &mut F) -> Result<Vec<mxcfb_rect>, &'static str> {
+        let op = match self.redo.pop_back() {
+            Some(op) => op,
+            None => return Ok(Vec::new()),
+        };
+        match UndoStack::apply(fb, &op) {
+            Ok(rects) => {
+                self.undo.push_back(op);
+                Ok(rects)
+            }
+            Err(e) => {
+                self.redo.push_back(op);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Bytes taken up by the compressed deltas of both the undo and redo history
+    pub fn memory_used(&self) -> usize {
+        self.memory_used
+    }
+
+    pub fn set_memory_budget(&mut self, memory_budget: usize) {
+        self.memory_budget = memory_budget;
+        self.enforce_budget();
+    }
+
+    /// Drops the whole history along with the operation in progress
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.pending.clear();
+        self.memory_used = 0;
+    }
+
+    /// XORs the deltas of `op` into the framebuffer. They are all decompressed upfront, and
+    /// if writing one of them back fails, the ones already written are XORed again to undo
+    /// them, so that the operation is applied either entirely or not at all.
+    fn apply<F: FramebufferIO>(
+        fb: &mut F,
+        op: &Operation,
+    ) -> Result<Vec<mxcfb_rect>, &'static str> {
+        let mut xors = Vec::with_capacity(op.deltas.len());
+        for delta in &op.deltas {
+            match zstd::decode_all(&*delta.data) {
+                Ok(xor) => xors.push(xor),
+                Err(_) => return Err("Failed to decompress the undo delta"),
+            };
+        }
+
+        let mut rects = Vec::with_capacity(op.deltas.len());
+        for (delta, xor) in op.deltas.iter().zip(xors.iter()) {
+            if let Err(e) = UndoStack::apply_delta(fb, delta.rect, xor) {
+                for (rect, xor) in rects.iter().zip(xors.iter()).rev() {
+                    if UndoStack::apply_delta(fb, *rect, xor).is_err() {
+                        error!("Failed to roll back a partially applied undo operation");
+                    }
+                }
+                return Err(e);
+            }
+            rects.push(delta.rect);
+        }
+        Ok(rects)
+    }
+
+    fn apply_delta<F: FramebufferIO>(
+        fb: &mut F,
+        rect: mxcfb_rect,
+        xor: &[u8],
+    ) -> Result<(), &'static str> {
+        let mut contents = fb.dump_region(rect)?;
+        if xor.len() != contents.len() {
+            return Err("Undo delta doesn't match the size of its region");
+        }
+        contents
+            .iter_mut()
+            .zip(xor.iter())
+            .for_each(|(c, x)| *c ^= *x);
+        fb.restore_region(rect, &contents).map(|_| ())
+    }
+
+    /// Drops the oldest operations, then the earliest undone ones, until the history fits in
+    /// the budget
+    fn enforce_budget(&mut self) {
+        while self.memory_used > self.memory_budget {
+            match self.undo.pop_front().or_else(|| self.redo.pop_front()) {
+                Some(op) => self.memory_used -= op.size,
+                None => break,
+            }
+        }
+    }
+}
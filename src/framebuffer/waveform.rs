@@ -0,0 +1,249 @@
+use std::fs::File;
+use std::io::Read;
+use std::time::Duration;
+
+use framebuffer::common::waveform_mode;
+
+/// Size of the header at the start of the waveform file
+const HEADER_SIZE: usize = 48;
+/// Every frame of a lookup table holds a phase for each of the 16x16 gray level transitions
+const FRAME_SIZE: usize = 256;
+/// Number of gray levels the lookup tables cover
+pub const GRAY_LEVELS: usize = 16;
+/// The frame rate the EPDC drives the reMarkable panel at
+pub const DEFAULT_FRAME_RATE: u32 = 85;
+
+/// Header of an EPDC waveform file, as defined in `struct waveform_data_header` of the driver
+#[derive(Clone, Debug, PartialEq)]
+pub struct WaveformHeader {
+    /// Waveform information words, identifying the panel and the waveform version
+    pub wi: [u32; 7],
+    pub xwia: u32,
+    pub cs1: u8,
+    pub wmta: u32,
+    /// Format version
+    pub fvsn: u8,
+    pub luts: u8,
+    /// Number of modes, minus one
+    pub mc: u8,
+    /// Number of temperature ranges, minus one
+    pub trc: u8,
+    pub eb: u8,
+    pub sb: u8,
+    pub cs2: u8,
+}
+
+impl WaveformHeader {
+    pub fn mode_count(&self) -> usize {
+        self.mc as usize + 1
+    }
+
+    pub fn temperature_range_count(&self) -> usize {
+        self.trc as usize + 1
+    }
+}
+
+/// A range of panel temperatures sharing the same lookup tables, in degrees Celsius.
+/// `min` is inclusive and `max` exclusive.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TemperatureRange {
+    pub min: u8,
+    pub max: u8,
+}
+
+impl TemperatureRange {
+    pub fn contains(&self, celsius: i32) -> bool {
+        celsius >= i32::from(self.min) && celsius < i32::from(self.max)
+    }
+}
+
+/// The sequence of frames a mode drives the panel with in a temperature range. Each frame
+/// holds a phase for every transition between two gray levels: 0 leaves the pixel alone,
+/// 1 drives it towards black and 2 towards white.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lut {
+    frames: Vec<[u8; FRAME_SIZE]>,
+}
+
+impl Lut {
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// The phase of `frame` for pixels going from gray level `from` to `to`, where 0 is
+    /// black and 15 is white
+    pub fn phase(&self, frame: usize, from: usize, to: usize) -> u8 {
+        self.frames[frame][to * GRAY_LEVELS + from]
+    }
+
+    /// Number of frames that drive the transition from `from` to `to`, i.e. the number of
+    /// frames after which a pixel going through that transition has settled
+    pub fn transition_frames(&self, from: usize, to: usize) -> usize {
+        self.frames
+            .iter()
+            .rposition(|f| f[to * GRAY_LEVELS + from] != 0)
+            .map_or(0, |last| last + 1)
+    }
+
+    /// How long the whole sequence takes at `frame_rate` frames per second
+    pub fn duration(&self, frame_rate: u32) -> Duration {
+        let micros =
+            self.frames.len() as u64 * 1_000_000 / u64::from(::std::cmp::max(frame_rate, 1));
+        Duration::from_micros(micros)
+    }
+}
+
+/// The lookup tables of a mode, one per temperature range
+#[derive(Clone, Debug, PartialEq)]
+pub struct WaveformMode {
+    pub luts: Vec<Lut>,
+}
+
+/// A parsed EPDC waveform file such as `/lib/firmware/epdc/epdc_ES103CS1.fw`.
+///
+/// The modes are in the order the EPDC indexes them with the `waveform_mode` of an update,
+/// so on the reMarkable mode 0 is `WAVEFORM_MODE_INIT`, mode 1 `WAVEFORM_MODE_DU` and so on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WaveformFile {
+    pub header: WaveformHeader,
+    pub temperature_ranges: Vec<TemperatureRange>,
+    pub modes: Vec<WaveformMode>,
+}
+
+fn read_le(data: &[u8]) -> u64 {
+    data.iter()
+        .rev()
+        .fold(0u64, |value, &byte| (value << 8) | u64::from(byte))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    read_le(&data[offset..offset + 4]) as u32
+}
+
+/// The waveform data is a tree of 64-bit offsets relative to its start
+fn read_offset(data: &[u8], offset: usize) -> Result<usize, &'static str> {
+    if offset + 8 > data.len() {
+        return Err("Waveform offset table is truncated");
+    }
+    let value = read_le(&data[offset..offset + 8]) as usize;
+    if value >= data.len() {
+        return Err("Waveform offset points past the end of the file");
+    }
+    Ok(value)
+}
+
+impl WaveformFile {
+    pub fn open(path: &str) -> Result<WaveformFile, &'static str> {
+        let mut data = Vec::new();
+        match File::open(path) {
+            Ok(mut f) => {
+                if f.read_to_end(&mut data).is_err() {
+                    return Err("Failed to read the waveform file");
+                }
+            }
+            Err(_) => return Err("Failed to open the waveform file"),
+        }
+        WaveformFile::parse(&data)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<WaveformFile, &'static str> {
+        if data.len() < HEADER_SIZE {
+            return Err("Waveform file is too short to hold a header");
+        }
+        let mut wi = [0u32; 7];
+        for (i, word) in wi.iter_mut().enumerate() {
+            *word = read_u32(data, i * 4);
+        }
+        let xwia = read_u32(data, 28);
+        let wmta = read_u32(data, 32);
+        let header = WaveformHeader {
+            wi,
+            xwia: xwia & 0x00ff_ffff,
+            cs1: (xwia >> 24) as u8,
+            wmta: wmta & 0x00ff_ffff,
+            fvsn: (wmta >> 24) as u8,
+            luts: data[36],
+            mc: data[37],
+            trc: data[38],
+            eb: data[40],
+            sb: data[41],
+            cs2: data[47],
+        };
+
+        // The temperature range table holds the lower bound of every range followed by
+        // the upper bound of the last one
+        let trt_entries = header.temperature_range_count();
+        let buffer_start = HEADER_SIZE + trt_entries + 1;
+        if data.len() < buffer_start {
+            return Err("Waveform file is too short to hold its temperature range table");
+        }
+        let bounds = &data[HEADER_SIZE..buffer_start];
+        let temperature_ranges = bounds
+            .windows(2)
+            .map(|w| TemperatureRange {
+                min: w[0],
+                max: w[1],
+            })
+            .collect();
+
+        let buffer = &data[buffer_start..];
+        let mut modes = Vec::with_capacity(header.mode_count());
+        for mode in 0..header.mode_count() {
+            let mode_table = read_offset(buffer, mode * 8)?;
+            let mut luts = Vec::with_capacity(trt_entries);
+            for range in 0..trt_entries {
+                let lut = read_offset(buffer, mode_table + range * 8)?;
+                let frames_start = lut + 8;
+                if frames_start > buffer.len() {
+                    return Err("Waveform lookup table is truncated");
+                }
+                let frame_count = read_le(&buffer[lut..frames_start]) as usize;
+                if frame_count > (buffer.len() - frames_start) / FRAME_SIZE {
+                    return Err("Waveform lookup table is truncated");
+                }
+                let frames = buffer[frames_start..frames_start + frame_count * FRAME_SIZE]
+                    .chunks(FRAME_SIZE)
+                    .map(|chunk| {
+                        let mut frame = [0u8; FRAME_SIZE];
+                        frame.copy_from_slice(chunk);
+                        frame
+                    })
+                    .collect();
+                luts.push(Lut { frames });
+            }
+            modes.push(WaveformMode { luts });
+        }
+
+        Ok(WaveformFile {
+            header,
+            temperature_ranges,
+            modes,
+        })
+    }
+
+    /// Returns the mode the EPDC uses for `waveform`, if the file has one
+    pub fn mode(&self, waveform: waveform_mode) -> Option<&WaveformMode> {
+        self.modes.get(waveform as usize)
+    }
+
+    /// Returns the index of the temperature range `celsius` falls into. Temperatures outside
+    /// of the table are clamped to its first or last range, as the driver does.
+    pub fn temperature_index(&self, celsius: i32) -> Option<usize> {
+        let first = self.temperature_ranges.first()?;
+        if celsius < i32::from(first.min) {
+            return Some(0);
+        }
+        Some(
+            self.temperature_ranges
+                .iter()
+                .position(|r| r.contains(celsius))
+                .unwrap_or(self.temperature_ranges.len() - 1),
+        )
+    }
+
+    /// Returns the lookup table the EPDC drives `waveform` with at `celsius` degrees
+    pub fn lut(&self, waveform: waveform_mode, celsius: i32) -> Option<&Lut> {
+        let index = self.temperature_index(celsius)?;
+        self.mode(waveform)?.luts.get(index)
+    }
+}
@@ -0,0 +1,133 @@
+use evdev;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use input::InputDevice;
+
+const INPUT_DIR: &str = "/dev/input";
+
+/// Key codes from `linux/input-event-codes.h` the devices are recognized by
+const BTN_TOOL_PEN: usize = 0x140;
+const KEY_HOME: usize = 102;
+const KEY_LEFT: usize = 105;
+const KEY_RIGHT: usize = 106;
+const KEY_POWER: usize = 116;
+const KEY_WAKEUP: usize = 143;
+
+/// Substrings of the names the drivers of the reMarkable give their devices
+const NAME_HINTS: [(&str, InputDevice); 6] = [
+    ("wacom", InputDevice::Wacom),
+    ("cyttsp5_mt", InputDevice::Multitouch),
+    ("pt_mt", InputDevice::Multitouch),
+    ("gpio-keys", InputDevice::GPIO),
+    ("gpio_keys", InputDevice::GPIO),
+    ("powerkey", InputDevice::GPIO),
+];
+
+/// The path `EvDevContext` used for each device before they were discovered, which is
+/// where they are on the reMarkable 1 when nothing else is attached
+pub fn legacy_path(device: InputDevice) -> Option<PathBuf> {
+    let node = match device {
+        InputDevice::Wacom => "event0",
+        InputDevice::Multitouch => "event1",
+        InputDevice::GPIO => "event2",
+        InputDevice::Unknown => return None,
+    };
+    Some(Path::new(INPUT_DIR).join(node))
+}
+
+/// Tells which `InputDevice` `device` is, going by its name first and by the events it
+/// advertises otherwise: a pen tool for the digitizer, multitouch positions for the touch
+/// screen and the keys of the buttons for the GPIO keys.
+pub fn classify(device: &evdev::Device) -> InputDevice {
+    let name = device.name().to_string_lossy().to_lowercase();
+    for &(hint, kind) in NAME_HINTS.iter() {
+        if name.contains(hint) {
+            return kind;
+        }
+    }
+
+    let keys = device.keys_supported();
+    let absolute = device.events_supported().contains(evdev::ABSOLUTE);
+    let axes = device.absolute_axes_supported();
+    if absolute && keys.contains(BTN_TOOL_PEN) {
+        InputDevice::Wacom
+    } else if absolute && axes.contains(evdev::ABS_MT_POSITION_X | evdev::ABS_MT_POSITION_Y) {
+        InputDevice::Multitouch
+    } else if device.events_supported().contains(evdev::KEY)
+        && [KEY_HOME, KEY_LEFT, KEY_RIGHT, KEY_POWER, KEY_WAKEUP]
+            .iter()
+            .any(|&key| keys.contains(key))
+    {
+        InputDevice::GPIO
+    } else {
+        InputDevice::Unknown
+    }
+}
+
+/// The number of an `eventN` node, for visiting them in order
+fn event_number(path: &Path) -> Option<u32> {
+    let name = path.file_name()?.to_str()?;
+    if !name.starts_with("event") {
+        return None;
+    }
+    name["event".len()..].parse().ok()
+}
+
+/// Opens every `/dev/input/event*` node and returns the path of each kind of device found.
+/// When several nodes are of the same kind, the lowest numbered one wins.
+pub fn scan() -> HashMap<InputDevice, PathBuf> {
+    let mut nodes: Vec<(u32, PathBuf)> = match fs::read_dir(INPUT_DIR) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter_map(|path| event_number(&path).map(|n| (n, path)))
+            .collect(),
+        Err(e) => {
+            warn!("Failed to list {}: {}", INPUT_DIR, e);
+            return HashMap::new();
+        }
+    };
+    nodes.sort();
+
+    let mut found = HashMap::new();
+    for (_, path) in nodes {
+        let device = match evdev::Device::open(&path) {
+            Ok(device) => device,
+            Err(e) => {
+                debug!("Skipping {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        let kind = classify(&device);
+        debug!(
+            "{} is {:?} ({})",
+            path.display(),
+            kind,
+            device.name().to_string_lossy()
+        );
+        if kind != InputDevice::Unknown && !found.contains_key(&kind) {
+            found.insert(kind, path);
+        }
+    }
+    found
+}
+
+/// Returns the path of `device` among the ones `discovered` by `scan()`, falling back to
+/// its `legacy_path(..)` if it wasn't discovered
+pub fn find(discovered: &HashMap<InputDevice, PathBuf>, device: InputDevice) -> Option<PathBuf> {
+    match discovered.get(&device) {
+        Some(path) => Some(path.clone()),
+        None => {
+            let path = legacy_path(device)?;
+            warn!(
+                "Couldn't discover {:?}, falling back to {}",
+                device,
+                path.display()
+            );
+            Some(path)
+        }
+    }
+}
@@ -3,11 +3,16 @@ use evdev;
 use input;
 use std;
 
+use input::recording::SharedRecorder;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 pub struct EvDevContext {
     device: input::InputDevice,
+    /// Overrides the discovery of the device, see `input::discovery`
+    path: Option<PathBuf>,
+    recorder: Option<SharedRecorder>,
     pub state: input::InputDeviceState,
     pub tx: std::sync::mpsc::Sender<input::InputEvent>,
     exit_requested: Arc<AtomicBool>,
@@ -40,6 +45,8 @@ impl EvDevContext {
     ) -> EvDevContext {
         EvDevContext {
             device,
+            path: None,
+            recorder: None,
             tx,
             state: input::InputDeviceState::new(device),
             started: Arc::new(AtomicBool::new(false)),
@@ -48,17 +55,33 @@ impl EvDevContext {
         }
     }
 
-    /// Non-blocking function that will open the provided path and wait for more data with epoll
+    /// Like `new(..)`, but reads from the evdev node at `path` instead of discovering it
+    pub fn with_path(
+        device: input::InputDevice,
+        path: PathBuf,
+        tx: std::sync::mpsc::Sender<input::InputEvent>,
+    ) -> EvDevContext {
+        let mut ctx = EvDevContext::new(device, tx);
+        ctx.path = Some(path);
+        ctx
+    }
+
+    /// Writes every raw event read from the device into the recorder `recorder` holds at
+    /// the time, before decoding it. Takes effect the next time the device is started.
+    pub fn set_recorder(&mut self, recorder: Option<SharedRecorder>) {
+        self.recorder = recorder;
+    }
+
+    /// Non-blocking function that will open the device and wait for more data with epoll.
+    /// Unless a path was provided, the device is looked up with `input::discovery::find(..)`.
     pub fn start(&mut self) {
         self.started.store(true, Ordering::Relaxed);
         self.exited.store(false, Ordering::Relaxed);
         self.exit_requested.store(false, Ordering::Relaxed);
 
-        let path = match self.device {
-            input::InputDevice::Wacom => "/dev/input/event0",
-            input::InputDevice::Multitouch => "/dev/input/event1",
-            input::InputDevice::GPIO => "/dev/input/event2",
-            _ => unreachable!(),
+        let path = match self.path {
+            Some(ref path) => path.clone(),
+            None => input::discovery::find(&input::discovery::scan(), self.device).unwrap(),
         };
 
         match evdev::Device::open(&path) {
@@ -75,13 +98,14 @@ impl EvDevContext {
                 epoll::ctl(epfd, epoll::ControlOptions::EPOLL_CTL_ADD, dev.fd(), v[0]).unwrap();
 
                 // init callback
-                info!("Init complete for {0}", String::from(path));
+                info!("Init complete for {0}", path.display());
 
                 let exit_req = Arc::clone(&self.exit_requested);
                 let exited = Arc::clone(&self.exited);
                 let device_type = self.device;
                 let state = self.state.clone();
                 let tx = self.tx.clone();
+                let recorder = self.recorder.clone();
                 let _ = std::thread::spawn(move || {
                     while !exit_req.load(Ordering::Relaxed) {
                         // -1 indefinite wait but it is okay because our EPOLL FD
@@ -92,16 +116,15 @@ impl EvDevContext {
                         }
 
                         for ev in dev.events_no_sync().unwrap() {
-                            // event callback
-                            let decoded_event = match device_type {
-                                input::InputDevice::Multitouch => {
-                                    input::multitouch::decode(&ev, &state)
+                            if let Some(ref recorder) = recorder {
+                                if let Some(ref recorder) = *recorder.read().unwrap() {
+                                    if let Err(e) = recorder.record(device_type, &ev) {
+                                        error!("Failed to record the input event: {0}", e);
+                                    }
                                 }
-                                input::InputDevice::Wacom => input::wacom::decode(&ev, &state),
-                                input::InputDevice::GPIO => input::gpio::decode(&ev, &state),
-                                _ => unreachable!(),
-                            };
-                            if let Some(event) = decoded_event {
+                            }
+                            // event callback
+                            if let Some(event) = input::decode(device_type, &ev, &state) {
                                 match tx.send(event) {
                                     Ok(_) => {}
                                     Err(e) => error!(
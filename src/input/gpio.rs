@@ -50,23 +50,23 @@ pub fn decode(ev: &input_event, outer_state: &InputDeviceState) -> Option<InputE
             let (p, before_state) = match ev.code {
                 102 => (
                     PhysicalButton::MIDDLE,
-                    state.states[0].fetch_and(ev.value != 0, Ordering::Relaxed),
+                    state.states[0].swap(ev.value != 0, Ordering::Relaxed),
                 ),
                 105 => (
                     PhysicalButton::LEFT,
-                    state.states[1].fetch_and(ev.value != 0, Ordering::Relaxed),
+                    state.states[1].swap(ev.value != 0, Ordering::Relaxed),
                 ),
                 106 => (
                     PhysicalButton::RIGHT,
-                    state.states[2].fetch_and(ev.value != 0, Ordering::Relaxed),
+                    state.states[2].swap(ev.value != 0, Ordering::Relaxed),
                 ),
                 116 => (
                     PhysicalButton::POWER,
-                    state.states[3].fetch_and(ev.value != 0, Ordering::Relaxed),
+                    state.states[3].swap(ev.value != 0, Ordering::Relaxed),
                 ),
                 143 => (
                     PhysicalButton::WAKEUP,
-                    state.states[4].fetch_and(ev.value != 0, Ordering::Relaxed),
+                    state.states[4].swap(ev.value != 0, Ordering::Relaxed),
                 ),
                 _ => return None,
             };
@@ -11,7 +11,13 @@ pub mod gpio;
 /// Contains the code to decode multitouch events
 pub mod multitouch;
 
-#[derive(PartialEq, Copy, Clone)]
+/// Contains the code to find the evdev node of each `InputDevice`
+pub mod discovery;
+
+/// Contains the code to record the raw input events and replay them through the decoders
+pub mod recording;
+
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug)]
 pub enum InputDevice {
     Wacom,
     Multitouch,
@@ -25,6 +31,7 @@ pub enum InputDeviceState {
     GPIOState(std::sync::Arc<gpio::GPIOState>),
 }
 
+use evdev;
 use std;
 use std::sync::Arc;
 impl Clone for InputDeviceState {
@@ -58,6 +65,20 @@ impl InputDeviceState {
     }
 }
 
+/// Decodes `ev` with the decoder of `device`, which updates `state` along the way
+pub fn decode(
+    device: InputDevice,
+    ev: &evdev::raw::input_event,
+    state: &InputDeviceState,
+) -> Option<InputEvent> {
+    match device {
+        InputDevice::Multitouch => multitouch::decode(ev, state),
+        InputDevice::Wacom => wacom::decode(ev, state),
+        InputDevice::GPIO => gpio::decode(ev, state),
+        InputDevice::Unknown => unreachable!(),
+    }
+}
+
 #[derive(PartialEq, Clone)]
 pub enum InputEvent {
     WacomEvent { event: wacom::WacomEvent },
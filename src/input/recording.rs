@@ -0,0 +1,217 @@
+use evdev::raw::input_event;
+use libc;
+
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use input;
+use input::{InputDevice, InputDeviceState, InputEvent};
+
+/// Starts every recording, followed by the version of the format
+const MAGIC: &[u8; 8] = b"LRMINPUT";
+const VERSION: u8 = 1;
+/// Device, timestamp, type, code and value, in little endian
+const RECORD_SIZE: usize = 1 + 8 + 2 + 2 + 4;
+
+/// The `EV_SYN` event with the `SYN_REPORT` code ending each batch of events of a device
+const EV_SYN: u16 = 0;
+const SYN_REPORT: u16 = 0;
+
+/// The recorder the `EvDevContext`s of the input devices write to, if any. Replacing it
+/// takes effect right away, also for the devices already started.
+pub type SharedRecorder = Arc<RwLock<Option<Arc<InputRecorder>>>>;
+
+fn device_id(device: InputDevice) -> u8 {
+    match device {
+        InputDevice::Unknown => 0,
+        InputDevice::Wacom => 1,
+        InputDevice::Multitouch => 2,
+        InputDevice::GPIO => 3,
+    }
+}
+
+fn device_from_id(id: u8) -> InputDevice {
+    match id {
+        1 => InputDevice::Wacom,
+        2 => InputDevice::Multitouch,
+        3 => InputDevice::GPIO,
+        _ => InputDevice::Unknown,
+    }
+}
+
+/// A raw `input_event` as read from one of the input devices
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RecordedEvent {
+    pub device: InputDevice,
+    /// The kernel timestamp of the event, in microseconds since the epoch
+    pub timestamp_us: u64,
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+impl RecordedEvent {
+    pub fn new(device: InputDevice, ev: &input_event) -> RecordedEvent {
+        RecordedEvent {
+            device,
+            timestamp_us: ev.time.tv_sec as u64 * 1_000_000 + ev.time.tv_usec as u64,
+            kind: ev._type,
+            code: ev.code,
+            value: ev.value,
+        }
+    }
+
+    pub fn to_input_event(&self) -> input_event {
+        input_event {
+            time: libc::timeval {
+                tv_sec: (self.timestamp_us / 1_000_000) as libc::time_t,
+                tv_usec: (self.timestamp_us % 1_000_000) as libc::suseconds_t,
+            },
+            _type: self.kind,
+            code: self.code,
+            value: self.value,
+        }
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(device_id(self.device));
+        out.extend_from_slice(&self.timestamp_us.to_le_bytes());
+        out.extend_from_slice(&self.kind.to_le_bytes());
+        out.extend_from_slice(&self.code.to_le_bytes());
+        out.extend_from_slice(&self.value.to_le_bytes());
+    }
+
+    fn decode(record: &[u8]) -> RecordedEvent {
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(&record[1..9]);
+        let mut value = [0u8; 4];
+        value.copy_from_slice(&record[13..17]);
+        RecordedEvent {
+            device: device_from_id(record[0]),
+            timestamp_us: u64::from_le_bytes(timestamp),
+            kind: u16::from_le_bytes([record[9], record[10]]),
+            code: u16::from_le_bytes([record[11], record[12]]),
+            value: i32::from_le_bytes(value),
+        }
+    }
+}
+
+/// Writes the raw events of the input devices into a recording, which starts with
+/// `LRMINPUT` and a version byte followed by a fixed size record per event. Can be shared
+/// by the `EvDevContext`s of all the devices, see `ApplicationContext::record_input(..)`.
+///
+/// The writer is flushed at the end of each batch of events, so that a recording cut short
+/// by the process exiting only misses the batch in progress.
+pub struct InputRecorder {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl InputRecorder {
+    pub fn new(mut writer: Box<dyn Write + Send>) -> io::Result<InputRecorder> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        Ok(InputRecorder {
+            writer: Mutex::new(writer),
+        })
+    }
+
+    /// Records into the file at `path`, which gets truncated
+    pub fn create(path: &str) -> io::Result<InputRecorder> {
+        InputRecorder::new(Box::new(BufWriter::new(File::create(path)?)))
+    }
+
+    /// Records `ev`, flushing the writer if it is the `SYN_REPORT` ending a batch
+    pub fn record(&self, device: InputDevice, ev: &input_event) -> io::Result<()> {
+        let mut record = Vec::with_capacity(RECORD_SIZE);
+        RecordedEvent::new(device, ev).encode(&mut record);
+        let mut writer = self.writer.lock().unwrap();
+        writer.write_all(&record)?;
+        if ev._type == EV_SYN && ev.code == SYN_REPORT {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+}
+
+impl Drop for InputRecorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("Failed to flush the input recording: {}", e);
+        }
+    }
+}
+
+pub fn read_recording<R: Read>(mut reader: R) -> io::Result<Vec<RecordedEvent>> {
+    let mut header = [0u8; 9];
+    reader.read_exact(&mut header)?;
+    if &header[..8] != MAGIC || header[8] != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Not an input recording of a supported version",
+        ));
+    }
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    if data.len() % RECORD_SIZE != 0 {
+        warn!("Ignoring the truncated last event of the input recording");
+    }
+    Ok(data
+        .chunks(RECORD_SIZE)
+        .filter(|record| record.len() == RECORD_SIZE)
+        .map(RecordedEvent::decode)
+        .collect())
+}
+
+pub fn open_recording(path: &str) -> io::Result<Vec<RecordedEvent>> {
+    read_recording(BufReader::new(File::open(path)?))
+}
+
+/// Feeds `events` through the decoders of their devices, starting from a fresh state,
+/// and passes the resulting `InputEvent`s to `callback`.
+///
+/// With a `speed`, the events are spaced out as they were recorded, divided by `speed`,
+/// so 1.0 replays them at their original pace and 2.0 twice as fast. Without one, they
+/// are replayed as fast as possible.
+pub fn replay<F: FnMut(InputEvent)>(events: &[RecordedEvent], speed: Option<f64>, mut callback: F) {
+    let states = [
+        InputDeviceState::new(InputDevice::Wacom),
+        InputDeviceState::new(InputDevice::Multitouch),
+        InputDeviceState::new(InputDevice::GPIO),
+    ];
+    let mut previous: Option<u64> = None;
+    for event in events {
+        if let (Some(speed), Some(previous)) = (speed, previous) {
+            if speed > 0.0 && event.timestamp_us > previous {
+                let micros = ((event.timestamp_us - previous) as f64 / speed) as u64;
+                thread::sleep(Duration::from_micros(micros));
+            }
+        }
+        previous = Some(event.timestamp_us);
+
+        let state = match event.device {
+            InputDevice::Wacom => &states[0],
+            InputDevice::Multitouch => &states[1],
+            InputDevice::GPIO => &states[2],
+            InputDevice::Unknown => continue,
+        };
+        if let Some(decoded) = input::decode(event.device, &event.to_input_event(), state) {
+            callback(decoded);
+        }
+    }
+}
+
+/// Replays `events` as fast as possible and returns the `InputEvent`s they decode to,
+/// e.g. to compare them against the expected ones in a regression test
+pub fn decode_recording(events: &[RecordedEvent]) -> Vec<InputEvent> {
+    let mut decoded = Vec::new();
+    replay(events, None, |event| decoded.push(event));
+    decoded
+}
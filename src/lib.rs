@@ -38,6 +38,9 @@ macro_rules! end_bench {
 #[macro_use]
 extern crate log;
 
+#[macro_use]
+extern crate bitflags;
+
 #[macro_use]
 extern crate ioctl_gen;
 
@@ -52,6 +55,7 @@ extern crate zstd;
 pub extern crate cgmath;
 pub extern crate epoll;
 pub extern crate evdev;
+pub extern crate futures;
 pub extern crate image;
 pub extern crate line_drawing;
 pub extern crate stopwatch;
@@ -7,7 +7,7 @@ use image;
 use framebuffer::cgmath;
 use framebuffer::common;
 use framebuffer::common::{color, mxcfb_rect};
-use framebuffer::refresh::PartialRefreshMode;
+use framebuffer::refresh::{PartialRefreshMode, RefreshProfile};
 use framebuffer::FramebufferDraw;
 use framebuffer::FramebufferRefresh;
 
@@ -30,13 +30,13 @@ impl<'a> std::fmt::Debug for ActiveRegionHandler {
 #[derive(Clone, Copy)]
 pub enum UIConstraintRefresh {
     NoRefresh,
-    Refresh,
-    RefreshAndWait,
+    Refresh(RefreshProfile),
+    RefreshAndWait(RefreshProfile),
 }
 
 impl Default for UIConstraintRefresh {
     fn default() -> UIConstraintRefresh {
-        UIConstraintRefresh::Refresh
+        UIConstraintRefresh::Refresh(RefreshProfile::UI)
     }
 }
 
@@ -135,10 +135,7 @@ impl UIElementWrapper {
                     framebuffer.partial_refresh(
                         &rect,
                         PartialRefreshMode::Wait,
-                        common::waveform_mode::WAVEFORM_MODE_DU,
-                        common::display_temp::TEMP_USE_REMARKABLE_DRAW,
-                        common::dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
-                        0,
+                        &RefreshProfile::MONOCHROME,
                         false,
                     );
                 }
@@ -213,10 +210,7 @@ impl UIElementWrapper {
                 framebuffer.partial_refresh(
                     &last_rect,
                     PartialRefreshMode::Async,
-                    common::waveform_mode::WAVEFORM_MODE_DU,
-                    common::display_temp::TEMP_USE_REMARKABLE_DRAW,
-                    common::dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
-                    0,
+                    &RefreshProfile::MONOCHROME,
                     false,
                 );
             }
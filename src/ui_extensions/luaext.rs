@@ -5,7 +5,8 @@ use framebuffer::cgmath;
 use framebuffer::common::*;
 use framebuffer::core;
 
-use framebuffer::refresh::PartialRefreshMode;
+use framebuffer::refresh::{PartialRefreshMode, RefreshProfile};
+use framebuffer::temperature::TemperatureMode;
 
 use framebuffer::FramebufferDraw;
 use framebuffer::FramebufferIO;
@@ -22,12 +23,21 @@ macro_rules! get_current_framebuffer {
     };
 }
 
+/// What `fb.refresh(..)` used when passed `true`, before it took the name of a preset
+const LUA_DEEP_REFRESH: RefreshProfile = RefreshProfile {
+    temperature: TemperatureMode::Fixed(display_temp::TEMP_USE_PAPYRUS),
+    ..RefreshProfile::UI
+};
+
+/// `profile` is either the name of a `RefreshProfile` preset (`"ink"`, `"ui"`, `"image"`,
+/// `"clean"`, `"monochrome"` or `"content"`), or a boolean where `false` stands for `"ink"`
+/// and `true` for a deeper GC16_FAST refresh.
 pub fn lua_refresh(
     y: hlua::AnyLuaValue,
     x: hlua::AnyLuaValue,
     height: hlua::AnyLuaValue,
     width: hlua::AnyLuaValue,
-    deep: hlua::AnyLuaValue,
+    profile: hlua::AnyLuaValue,
     wait: hlua::AnyLuaValue,
 ) {
     if let (
@@ -35,10 +45,21 @@ pub fn lua_refresh(
         hlua::AnyLuaValue::LuaNumber(nx),
         hlua::AnyLuaValue::LuaNumber(nheight),
         hlua::AnyLuaValue::LuaNumber(nwidth),
-        hlua::AnyLuaValue::LuaBoolean(bdeep),
         hlua::AnyLuaValue::LuaBoolean(bwait),
-    ) = (y, x, height, width, deep, wait)
+    ) = (y, x, height, width, wait)
     {
+        let profile = match profile {
+            hlua::AnyLuaValue::LuaBoolean(true) => LUA_DEEP_REFRESH,
+            hlua::AnyLuaValue::LuaBoolean(false) => RefreshProfile::INK,
+            hlua::AnyLuaValue::LuaString(ref name) => match RefreshProfile::from_name(name) {
+                Some(profile) => profile,
+                None => {
+                    warn!("Unknown refresh profile passed to fb.refresh: {}", name);
+                    return;
+                }
+            },
+            _ => return,
+        };
         let framebuffer = get_current_framebuffer!();
         let rect = mxcfb_rect {
             top: ny as u32,
@@ -46,35 +67,16 @@ pub fn lua_refresh(
             height: nheight as u32,
             width: nwidth as u32,
         };
-        if bdeep {
-            framebuffer.partial_refresh(
-                &rect,
-                if bwait {
-                    PartialRefreshMode::Wait
-                } else {
-                    PartialRefreshMode::Async
-                },
-                waveform_mode::WAVEFORM_MODE_GC16_FAST,
-                display_temp::TEMP_USE_PAPYRUS,
-                dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
-                0,
-                false,
-            );
-        } else {
-            framebuffer.partial_refresh(
-                &rect,
-                if bwait {
-                    PartialRefreshMode::Wait
-                } else {
-                    PartialRefreshMode::Async
-                },
-                waveform_mode::WAVEFORM_MODE_DU,
-                display_temp::TEMP_USE_REMARKABLE_DRAW,
-                dither_mode::EPDC_FLAG_EXP1,
-                DRAWING_QUANT_BIT,
-                false,
-            );
-        }
+        framebuffer.partial_refresh(
+            &rect,
+            if bwait {
+                PartialRefreshMode::Wait
+            } else {
+                PartialRefreshMode::Async
+            },
+            &profile,
+            false,
+        );
     }
 }
 
@@ -130,11 +132,5 @@ pub fn lua_set_pixel(y: hlua::AnyLuaValue, x: hlua::AnyLuaValue, color: hlua::An
 pub fn lua_clear() {
     let framebuffer = get_current_framebuffer!();
     framebuffer.clear();
-    framebuffer.full_refresh(
-        waveform_mode::WAVEFORM_MODE_INIT,
-        display_temp::TEMP_USE_AMBIENT,
-        dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
-        0,
-        true,
-    );
+    framebuffer.full_refresh(&RefreshProfile::CLEAN, true);
 }
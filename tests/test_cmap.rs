@@ -0,0 +1,118 @@
+extern crate libremarkable;
+
+mod common;
+
+use std::io;
+
+use libremarkable::framebuffer::cmap::*;
+use libremarkable::framebuffer::common::{mxcfb_rect, FBIOGETCMAP, FBIOPUTCMAP};
+use libremarkable::framebuffer::core::Framebuffer;
+use libremarkable::framebuffer::device::*;
+use libremarkable::framebuffer::refresh::RefreshRequest;
+use libremarkable::framebuffer::FramebufferBase;
+
+use common::SharedBuffer;
+
+#[test]
+fn test_contrast_lut() {
+    let lut = contrast_lut(50, 200);
+    assert_eq!(lut[0], 0);
+    assert_eq!(lut[50], 0);
+    assert_eq!(lut[125], 127);
+    assert_eq!(lut[200], 255);
+    assert_eq!(lut[255], 255);
+    assert!(lut.windows(2).all(|w| w[0] <= w[1]));
+    assert_eq!(contrast_lut(200, 50)[..], identity_lut()[..]);
+}
+
+#[test]
+fn test_posterize_lut() {
+    let lut = posterize_lut(2);
+    assert_eq!((lut[0], lut[127], lut[128], lut[255]), (0, 0, 255, 255));
+    let lut = posterize_lut(4);
+    assert_eq!(
+        (lut[0], lut[42], lut[43], lut[128], lut[255]),
+        (0, 0, 85, 170, 255)
+    );
+    assert!(posterize_lut(1).iter().all(|&v| v == 0));
+}
+
+#[test]
+fn test_gray_lut_round_trip() {
+    let lut = gamma_lut(2.2);
+    let colormap = Colormap::from_gray_lut(&lut);
+    assert_eq!(colormap.len(), GRAY_LUT_SIZE);
+    assert_eq!(colormap.gray_lut().unwrap()[..], lut[..]);
+    assert_eq!(Colormap::new(1, GRAY_LUT_SIZE).gray_lut(), None);
+}
+
+/// A framebuffer in the 8-bit grayscale mode, the only one the colormap applies to
+fn grayscale(device: Box<dyn FramebufferDevice>) -> Framebuffer<'static> {
+    let mut fb = Framebuffer::from_device(device);
+    fb.var_screen_info.bits_per_pixel = 8;
+    fb
+}
+
+#[test]
+fn test_colormap_needs_grayscale() {
+    let fb = Framebuffer::from_device(Box::new(MockDevice::new()));
+    assert_eq!(fb.var_screen_info.bits_per_pixel, 16);
+    let colormap = Colormap::from_gray_lut(&identity_lut());
+    assert_eq!(
+        fb.set_colormap(&colormap).unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+    let request = RefreshRequest::new(mxcfb_rect {
+        top: 0,
+        left: 0,
+        width: 8,
+        height: 8,
+    });
+    assert_eq!(
+        request.with_colormap(true).submit(&fb).unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+    assert!(request.submit(&fb).is_ok());
+}
+
+#[test]
+fn test_set_colormap_bounds() {
+    let fb = grayscale(Box::new(MockDevice::new()));
+    assert!(fb.set_colormap(&Colormap::new(0, GRAY_LUT_SIZE)).is_ok());
+    assert!(fb
+        .set_colormap(&Colormap::new(0, GRAY_LUT_SIZE + 1))
+        .is_err());
+    assert!(fb
+        .set_colormap(&Colormap::new(16, GRAY_LUT_SIZE - 8))
+        .is_err());
+}
+
+#[test]
+fn test_colormap_trace_replay() {
+    let colormap = Colormap::from_gray_lut(&contrast_lut(50, 200));
+    let trace = SharedBuffer::default();
+    {
+        let device = TracingDevice::new(Box::new(MockDevice::new()), Box::new(trace.clone()));
+        let fb = grayscale(Box::new(device));
+        fb.set_colormap(&colormap).unwrap();
+    }
+    let entries = read_trace(&trace.contents()[..]).unwrap();
+    // The colormap found is read before installing the new one
+    let get = entries
+        .iter()
+        .position(|e| e.request == FBIOGETCMAP)
+        .unwrap();
+    let put = &entries[get + 1];
+    assert_eq!(put.request, FBIOPUTCMAP);
+
+    // Answer a read of the colormap with the one that was installed
+    let mut replayed = entries[..get].to_vec();
+    replayed.push(TraceEntry {
+        request: FBIOGETCMAP,
+        input: Vec::new(),
+        output: put.input.clone(),
+        ..put.clone()
+    });
+    let fb = Framebuffer::from_device(Box::new(ReplayDevice::new(replayed)));
+    assert_eq!(fb.get_colormap().unwrap(), colormap);
+}
@@ -0,0 +1,139 @@
+extern crate libremarkable;
+extern crate mmap;
+
+use std::io;
+
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::core::Framebuffer;
+use libremarkable::framebuffer::device::{FramebufferDevice, MockDevice};
+use libremarkable::framebuffer::mxcfb::mxcfb_alt_buffer_data;
+use libremarkable::framebuffer::refresh::*;
+use libremarkable::framebuffer::screeninfo::VarScreeninfo;
+use libremarkable::framebuffer::FramebufferDraw;
+
+const REGION: mxcfb_rect = mxcfb_rect {
+    top: 0,
+    left: 0,
+    width: 128,
+    height: 128,
+};
+
+/// Two pages, the first one black and white and the second one holding every gray level
+fn two_pages() -> Framebuffer<'static> {
+    let mut fb = Framebuffer::from_device_paged(Box::new(MockDevice::new()), 2);
+    assert_eq!(fb.page_count(), 2);
+    fb.clear();
+    fb.set_draw_page(1).unwrap();
+    fb.clear();
+    for i in 0..16u8 {
+        fb.fill_rect(
+            cgmath::Point2 {
+                x: i as i32 * 8,
+                y: 0,
+            },
+            cgmath::Vector2 { x: 8, y: 128 },
+            color::GRAY(i * 16 + 8),
+        );
+    }
+    fb
+}
+
+/// Rejects every virtual resolution taller than a single screen in either orientation
+struct SinglePageDevice(MockDevice);
+
+impl FramebufferDevice for SinglePageDevice {
+    unsafe fn ioctl(&self, request: NativeWidthType, arg: &mut [u8]) -> io::Result<()> {
+        if request == FBIOPUT_VSCREENINFO {
+            let var = (arg.as_ptr() as *const VarScreeninfo).read_unaligned();
+            if var.yres_virtual > 1872 {
+                return Err(io::Error::from_raw_os_error(22));
+            }
+        }
+        self.0.ioctl(request, arg)
+    }
+
+    fn map(&self, len: usize) -> io::Result<mmap::MemoryMap> {
+        self.0.map(len)
+    }
+}
+
+#[test]
+fn test_page_count() {
+    // The memory of the mock holds two screens, after the rotation as well
+    let fb = Framebuffer::from_device_paged(Box::new(MockDevice::new()), 3);
+    assert_eq!(fb.page_count(), 2);
+    assert_eq!(fb.var_screen_info.yres, 1872);
+    assert_eq!(fb.var_screen_info.yres_virtual, 1872 * 2);
+    assert!(fb.set_draw_page(1).is_ok());
+    assert!(fb.set_draw_page(2).is_err());
+
+    let fb = Framebuffer::from_device(Box::new(MockDevice::new()));
+    assert_eq!(fb.page_count(), 1);
+
+    let fb = Framebuffer::from_device_paged(Box::new(SinglePageDevice(MockDevice::new())), 2);
+    assert_eq!(fb.page_count(), 1);
+    assert_eq!(fb.var_screen_info.yres, 1872);
+    assert!(fb.pan_to_page(0).is_ok());
+    assert!(fb.pan_to_page(1).is_err());
+}
+
+#[test]
+fn test_select_waveform_samples_displayed_page() {
+    let fb = two_pages();
+    assert_eq!(fb.draw_page(), 1);
+    assert_eq!(fb.displayed_page(), 0);
+    let refreshed = RefreshRequest::new(REGION)
+        .with_profile(&RefreshProfile::CONTENT)
+        .submit(&fb)
+        .unwrap();
+    assert_eq!(refreshed.waveform_mode, waveform_mode::WAVEFORM_MODE_DU);
+
+    fb.pan_to_page(1).unwrap();
+    let refreshed = RefreshRequest::new(REGION)
+        .with_profile(&RefreshProfile::CONTENT)
+        .submit(&fb)
+        .unwrap();
+    assert_eq!(refreshed.waveform_mode, waveform_mode::WAVEFORM_MODE_GC16);
+    assert_eq!(
+        select_waveform_on_page(&fb, 0, &REGION).waveform_mode,
+        waveform_mode::WAVEFORM_MODE_DU
+    );
+}
+
+#[test]
+fn test_select_waveform_samples_alt_buffer_page() {
+    let fb = two_pages();
+    let page_length = fb.fix_screen_info.line_length * fb.var_screen_info.yres;
+    let alt_buffer = |page: u32| mxcfb_alt_buffer_data {
+        phys_addr: fb.fix_screen_info.smem_start as u32 + page * page_length,
+        width: fb.fix_screen_info.line_length * 8 / fb.var_screen_info.bits_per_pixel,
+        height: fb.var_screen_info.yres,
+        alt_update_region: REGION,
+    };
+
+    let refreshed = RefreshRequest::new(REGION)
+        .with_profile(&RefreshProfile::CONTENT)
+        .with_alt_buffer(alt_buffer(1))
+        .submit(&fb)
+        .unwrap();
+    assert_eq!(refreshed.waveform_mode, waveform_mode::WAVEFORM_MODE_GC16);
+    let refreshed = RefreshRequest::new(REGION)
+        .with_profile(&RefreshProfile::CONTENT)
+        .with_alt_buffer(alt_buffer(0))
+        .submit(&fb)
+        .unwrap();
+    assert_eq!(refreshed.waveform_mode, waveform_mode::WAVEFORM_MODE_DU);
+
+    // Past the mapped pages, so there is nothing to sample
+    let refreshed = RefreshRequest::new(REGION)
+        .with_profile(&RefreshProfile::CONTENT)
+        .with_alt_buffer(alt_buffer(2))
+        .submit(&fb)
+        .unwrap();
+    assert!(refreshed.selection.is_none());
+    assert_eq!(
+        refreshed.waveform_mode,
+        RefreshProfile::CONTENT.waveform_mode
+    );
+}
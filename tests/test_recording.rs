@@ -0,0 +1,89 @@
+extern crate libc;
+extern crate libremarkable;
+
+mod common;
+
+use std::io::BufWriter;
+
+use libremarkable::evdev::raw::input_event;
+use libremarkable::input::gpio::{GPIOEvent, PhysicalButton};
+use libremarkable::input::recording::*;
+use libremarkable::input::{InputDevice, InputEvent};
+
+use common::SharedBuffer;
+
+fn event(timestamp_us: i64, kind: u16, code: u16, value: i32) -> input_event {
+    input_event {
+        time: libc::timeval {
+            tv_sec: (timestamp_us / 1_000_000) as libc::time_t,
+            tv_usec: (timestamp_us % 1_000_000) as libc::suseconds_t,
+        },
+        _type: kind,
+        code,
+        value,
+    }
+}
+
+#[test]
+fn test_record_and_decode() {
+    // KEY_HOME pressed, then released, each followed by a SYN_REPORT
+    let raw = [
+        event(1_000_000, 1, 102, 1),
+        event(1_000_010, 0, 0, 0),
+        event(1_250_000, 1, 102, 0),
+        event(1_250_010, 0, 0, 0),
+    ];
+    let buffer = SharedBuffer::default();
+    {
+        let recorder = InputRecorder::new(Box::new(buffer.clone())).unwrap();
+        for ev in raw.iter() {
+            recorder.record(InputDevice::GPIO, ev).unwrap();
+        }
+    }
+
+    let events = read_recording(&buffer.contents()[..]).unwrap();
+    assert_eq!(events.len(), raw.len());
+    for (recorded, ev) in events.iter().zip(raw.iter()) {
+        assert_eq!(*recorded, RecordedEvent::new(InputDevice::GPIO, ev));
+        assert_eq!(recorded.to_input_event().time.tv_usec, ev.time.tv_usec);
+    }
+
+    let decoded = decode_recording(&events);
+    assert_eq!(decoded.len(), 2);
+    assert!(
+        decoded[0]
+            == InputEvent::GPIO {
+                event: GPIOEvent::Press {
+                    button: PhysicalButton::MIDDLE,
+                },
+            }
+    );
+    assert!(
+        decoded[1]
+            == InputEvent::GPIO {
+                event: GPIOEvent::Unpress {
+                    button: PhysicalButton::MIDDLE,
+                },
+            }
+    );
+}
+
+#[test]
+fn test_flush_on_syn_report() {
+    let buffer = SharedBuffer::default();
+    let recorder = InputRecorder::new(Box::new(BufWriter::new(buffer.clone()))).unwrap();
+    recorder
+        .record(InputDevice::GPIO, &event(0, 1, 116, 1))
+        .unwrap();
+    assert!(buffer.contents().is_empty());
+    recorder
+        .record(InputDevice::GPIO, &event(10, 0, 0, 0))
+        .unwrap();
+    assert_eq!(read_recording(&buffer.contents()[..]).unwrap().len(), 2);
+}
+
+#[test]
+fn test_reject_other_files() {
+    assert!(read_recording(&b"LRMINPUT\xff"[..]).is_err());
+    assert!(read_recording(&b"garbage!!"[..]).is_err());
+}
@@ -0,0 +1,64 @@
+extern crate libremarkable;
+
+use std::io;
+
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::collision::CollisionPolicy;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::core::Framebuffer;
+use libremarkable::framebuffer::refresh::*;
+use libremarkable::framebuffer::simulator::SimulatorDevice;
+use libremarkable::framebuffer::{FramebufferDraw, FramebufferRefresh};
+
+const REGION: mxcfb_rect = mxcfb_rect {
+    top: 0,
+    left: 0,
+    width: 100,
+    height: 100,
+};
+
+#[test]
+fn test_du_leaves_gray_targets() {
+    let device = SimulatorDevice::new();
+    let panel = device.panel();
+    let mut fb = Framebuffer::from_device(Box::new(device));
+    fb.fill_rect(
+        cgmath::Point2 { x: 0, y: 0 },
+        cgmath::Vector2 { x: 50, y: 100 },
+        color::BLACK,
+    );
+    fb.fill_rect(
+        cgmath::Point2 { x: 50, y: 0 },
+        cgmath::Vector2 { x: 50, y: 100 },
+        color::GRAY(128),
+    );
+    RefreshRequest::new(REGION)
+        .with_profile(&RefreshProfile::MONOCHROME)
+        .submit(&fb)
+        .unwrap();
+    let image = panel.image();
+    assert!(image.get_pixel(25, 50).data[0] < 64);
+    assert_eq!(image.get_pixel(75, 50).data[0], 255);
+
+    RefreshRequest::new(REGION)
+        .with_profile(&RefreshProfile::IMAGE)
+        .submit(&fb)
+        .unwrap();
+    assert!(panel.image().get_pixel(75, 50).data[0] < 255);
+}
+
+#[test]
+fn test_simulated_time() {
+    let fb = Framebuffer::from_device(Box::new(SimulatorDevice::new()));
+    let first = RefreshRequest::new(REGION).submit(&fb).unwrap();
+
+    // The time stands still until an update is waited for
+    let overlapping = RefreshRequest::new(REGION).with_collision_policy(CollisionPolicy::Drop);
+    assert_eq!(
+        overlapping.submit(&fb).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+    fb.wait_refresh_complete(first.marker);
+    let second = overlapping.submit(&fb).unwrap();
+    assert_eq!(second.collisions, 0);
+}